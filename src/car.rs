@@ -4,11 +4,15 @@ use bevy::{
     math::{vec2, vec3},
     prelude::*,
 };
-use bevy_prototype_debug_lines::{DebugLines, DebugLinesPlugin};
+use bevy_prototype_debug_lines::DebugLines;
 use bevy_rapier2d::prelude::*;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use crate::nn::Net;
+use crate::enemy::EnemyType;
+use crate::genome::Genome;
+use crate::nn::{Activation, Activations, Memory};
+use crate::simulation::{SimSet, SimulationTick};
 use crate::*;
 
 pub struct CarPlugin;
@@ -18,10 +22,23 @@ pub struct Car;
 
 #[derive(Component)]
 pub struct Brain {
-    pub nn: Net,
-    pub nn_outputs: Vec<Vec<f64>>,
+    pub nn: Genome,
+    /// Values of every layer from the last prediction
+    pub activations: Activations,
+
+    sensor_inputs: Vec<f32>,
+    /// Where the speed sensor last saw the car
+    last_position: Option<Vec2>,
+    /// Recurrent state, starts blank on every spawn
+    memory: Memory,
+}
 
-    ray_inputs: Vec<f64>,
+/// Whether the sensors have filled the brain inputs since the car
+/// spawned, a car stands still until they have
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SensorState {
+    NotReady,
+    Ready,
 }
 
 #[derive(Component, Reflect)]
@@ -33,11 +50,51 @@ struct Speed(f32);
 #[derive(Component)]
 pub struct Fitness(pub f32);
 
+/// Brain carried over unchanged from the previous generation
+#[derive(Component)]
+pub struct Elite;
+
 #[derive(Resource, Default)]
 struct RayCastSensors(Vec<(f32, f32)>);
 
-// wasd controls
-struct CarControls(bool, bool, bool, bool);
+/// What a sensor ray hit, fed to the brain one hot when `sensors.ray_hit_class` is set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HitClass {
+    Wall,
+    SlowCar,
+    HorizontalCar,
+    Truck,
+}
+
+/// Driver inputs, from the keyboard or a brain
+pub struct CarControls {
+    /// 0..1
+    pub throttle: f32,
+    /// -1..1, positive turns left
+    pub steer: f32,
+    /// 0..1, takes over from the throttle
+    pub brake: f32,
+}
+
+/// How the brain outputs, throttle, steering and brake in that order, drive the car.
+/// Both expect outputs in 0..1
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlScheme {
+    /// Each output is a wasd key, pressed past its threshold
+    Discrete,
+    /// Outputs are analog amounts, steering is centered on 0.5
+    Continuous,
+}
+
+/// How the controls move a car
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DynamicsMode {
+    /// The transform is moved directly, at a fixed speed while the throttle is down
+    Position,
+    /// Speed builds up and bleeds off with throttle, brake and friction,
+    /// the car is moved by rapier through its velocity
+    Physics,
+}
 
 #[derive(Bundle)]
 pub struct CarBundle {
@@ -45,6 +102,7 @@ pub struct CarBundle {
     car: Car,
     fitness: Fitness,
     brain: Brain,
+    sensor_state: SensorState,
     turn_speed: TurnSpeed,
     speed: Speed,
     velocity: Velocity,
@@ -60,54 +118,61 @@ pub struct CarBundle {
 
 impl Plugin for CarPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(DebugLinesPlugin::default())
-            .register_type::<TurnSpeed>()
+        app.register_type::<TurnSpeed>()
             .register_type::<Speed>()
             .insert_resource(RayCastSensors::default())
             .add_startup_system(setup)
             // .add_system(car_manual_input_system)
-            .add_system(car_nn_controlled_system)
-            // .add_system(car_gas_system)
-            // .add_system(car_steer_system)
-            .add_system(collision_events_system)
-            .add_system(sensors_system);
+            .add_system(
+                car_nn_controlled_system
+                    .in_set(SimSet::Act)
+                    .in_schedule(SimulationTick),
+            )
+            .add_system(
+                car_gas_system
+                    .run_if(is_physics_dynamics)
+                    .after(car_nn_controlled_system)
+                    .in_set(SimSet::Act)
+                    .in_schedule(SimulationTick),
+            )
+            .add_system(
+                car_steer_system
+                    .run_if(is_physics_dynamics)
+                    .after(car_nn_controlled_system)
+                    .in_set(SimSet::Act)
+                    .in_schedule(SimulationTick),
+            )
+            .add_system(
+                collision_events_system
+                    .in_set(SimSet::Evaluate)
+                    .in_schedule(SimulationTick),
+            )
+            .add_system(
+                sensors_system
+                    .in_set(SimSet::Sense)
+                    .in_schedule(SimulationTick),
+            );
     }
 }
 
-fn position_based_movement_system(
-    controls: CarControls, 
-    transform: &mut Transform
-) {
-    let w_key = controls.0;
-    let a_key = controls.1;
-    let s_key = controls.2;
-    let d_key = controls.3;
-
-    let time_step = 1.0 / 60.0;
-    let mut rotation_factor = 0.0;
-    let mut movement_factor = 0.0;
-
-    if w_key {
-        movement_factor += 3.5;
-    }
-    if a_key {
-        rotation_factor += 0.5;
-    } else if d_key {
-        rotation_factor -= 0.5;
-    }
+fn position_based_movement_system(controls: CarControls, transform: &mut Transform) {
+    let time_step = SIM_TIMESTEP;
+    let rotation_factor = 0.5 * controls.steer;
+    let movement_factor = 3.5 * 60.0 * controls.throttle * (1.0 - controls.brake);
 
     transform.rotate_z(rotation_factor * 5.0 * time_step);
     let movement_direction = transform.rotation * Vec3::Y;
-    let movement_distance = movement_factor;
+    let movement_distance = movement_factor * time_step;
     let translation_delta = movement_direction * movement_distance;
     transform.translation += translation_delta;
 }
 
-fn setup(mut ray_cast_sensors: ResMut<RayCastSensors>) {
+fn setup(config: Res<SimConfig>, mut ray_cast_sensors: ResMut<RayCastSensors>) {
     // Pre compute the raycast directions
-    let angle_per_ray = RAYCAST_SPREAD_ANGLE_DEG / (NUM_RAY_CASTS as f32) + 1.0;
-    let mut current_angle = RAYCAST_START_ANGLE_DEG;
-    for _ in 0..NUM_RAY_CASTS {
+    let sensors = &config.sensors;
+    let angle_per_ray = sensors.raycast_spread_angle_deg / (sensors.num_ray_casts as f32) + 1.0;
+    let mut current_angle = sensors.raycast_start_angle_deg;
+    for _ in 0..sensors.num_ray_casts {
         let angle = current_angle * (PI / 180.0);
         let x = angle.cos();
         let y = angle.sin();
@@ -132,47 +197,61 @@ fn collision_events_system(
     }
 }
 
+/// What the brain reads and drives, per car
+type DrivenCar = (
+    Entity,
+    &'static SensorState,
+    &'static mut Speed,
+    &'static mut TurnSpeed,
+    &'static mut Brain,
+    &'static mut Transform,
+);
+
 fn car_nn_controlled_system(
-    time: Res<Time>,
-    mut car_query: Query<(&mut Speed, &mut TurnSpeed, &mut Brain, &mut Transform), With<Car>>,
+    mut commands: Commands,
+    config: Res<SimConfig>,
+    mut car_query: Query<DrivenCar, With<Car>>,
 ) {
-    for (mut speed, mut turn_speed, mut brain, mut transform) in car_query.iter_mut() {
-        if brain.ray_inputs.is_empty() {
+    for (entity, sensor_state, mut speed, mut turn_speed, mut brain, mut transform) in
+        car_query.iter_mut()
+    {
+        if *sensor_state == SensorState::NotReady {
             speed.0 = 0.0;
             turn_speed.0 = 0.0;
-            return;
+            continue;
         }
 
-        brain.nn_outputs = brain.nn.predict(&brain.ray_inputs);
-        let nn_out = brain.nn_outputs[NUM_OUPUT_NODES - 1].clone();
-        //  nn_out = brain.nn.predict(&brain.ray_inputs).pop().unwrap();
-
-        // let w_key = nn_out[0] >= NN_W_ACTIVATION_THRESHOLD;
-        let w_key = true;
-        // let s_key = nn_out[2] >= NN_S_ACTIVATION_THRESHOLD;
-        let s_key = false;
-        let mut a_key = false;
-        let mut d_key = false;
-
-        if nn_out[1] >= 0.5 {
-            a_key = true;
-        } else {
-            d_key = true;
+        let brain = &mut *brain;
+        let prediction = brain.nn.try_predict_into(
+            &brain.sensor_inputs,
+            &mut brain.memory,
+            &mut brain.activations,
+        );
+        // A brain that can't take the sensors is out of the generation, like a crash
+        if let Err(e) = prediction {
+            error!("Car {:?} can't drive: {}", entity, e);
+            commands.entity(entity).remove::<Car>();
+            continue;
         }
+        let controls = CarControls::from_nn_outputs(
+            brain.activations.output(),
+            brain.nn.output_activation(),
+            config.car.controls,
+        );
 
-        // update_car_input(
-        //     CarControls(w_key, a_key, s_key, d_key),
-        //     &mut turn_speed,
-        //     &mut speed,
-        //     &time,
-        // );
-        position_based_movement_system(CarControls(w_key, a_key, s_key, d_key), &mut transform);
+        apply_controls(
+            controls,
+            config.car.dynamics,
+            &mut turn_speed,
+            &mut speed,
+            &mut transform,
+        );
     }
 }
 
 #[allow(dead_code)]
 fn car_manual_input_system(
-    time: Res<Time>,
+    config: Res<SimConfig>,
     keyboard_input: Res<Input<KeyCode>>,
     mut car_query: Query<(&mut Speed, &mut TurnSpeed, &mut Transform), With<Car>>,
 ) {
@@ -181,55 +260,55 @@ fn car_manual_input_system(
         let a_key = keyboard_input.pressed(KeyCode::A);
         let s_key = keyboard_input.pressed(KeyCode::S);
         let d_key = keyboard_input.pressed(KeyCode::D);
-        update_car_input(
-            CarControls(
-                w_key, a_key, s_key, d_key
-            ),
+        let controls = CarControls::from_keys(w_key, a_key, s_key, d_key);
+        apply_controls(
+            controls,
+            config.car.dynamics,
             &mut turn_speed,
             &mut speed,
-            &time,
+            &mut transform,
         );
-        position_based_movement_system(CarControls(w_key, a_key, s_key, d_key), &mut transform);
     }
 }
 
-fn update_car_input(
+/// In physics mode only the speeds are updated, `car_gas_system`
+/// and `car_steer_system` then hand them to rapier
+fn apply_controls(
     controls: CarControls,
+    dynamics: DynamicsMode,
     turn_speed: &mut TurnSpeed,
     speed: &mut Speed,
-    time: &Time,
+    transform: &mut Transform,
 ) {
-    let w_key = controls.0;
-    let a_key = controls.1;
-    let s_key = controls.2;
-    let d_key = controls.3;
-
-    turn_speed.0 = if a_key {
-        TURN_SPEED
-    } else if d_key {
-        -TURN_SPEED
-    } else {
-        0.0
-    };
+    match dynamics {
+        DynamicsMode::Position => position_based_movement_system(controls, transform),
+        DynamicsMode::Physics => update_car_input(&controls, turn_speed, speed),
+    }
+}
+
+fn update_car_input(controls: &CarControls, turn_speed: &mut TurnSpeed, speed: &mut Speed) {
+    let time_step = SIM_TIMESTEP;
+    turn_speed.0 = TURN_SPEED * controls.steer;
+
+    // Throttle and brake pull against each other, friction only slows a coasting car
+    let accel = CAR_THRUST * controls.throttle - FRICTION * 1.2 * controls.brake;
+    let is_coasting = controls.throttle.max(controls.brake) < 0.01;
 
     // Friction code from: https://github.com/Rust-Ninja-Sabi/bevyastro
-    speed.0 = if s_key {
-        if speed.0.abs() <= 30.0 {
-            0.0
-        } else {
-            speed.0 - FRICTION * time.delta_seconds() * 1.2
-        }
-    } else if w_key {
-        speed.0 + CAR_THRUST * time.delta_seconds()
+    speed.0 = if !is_coasting && accel >= 0.0 {
+        speed.0 + accel * time_step
+    } else if !is_coasting {
+        // Braking stops the car, it doesn't reverse it
+        (speed.0 + accel * time_step).max(speed.0.min(0.0))
     } else {
         if speed.0.abs() <= 30.0 {
             // Avoid speed from over shooting
             // and be non zero all the time
             0.0
         } else if speed.0 > 0.0 {
-            speed.0 - FRICTION * time.delta_seconds()
+            speed.0 - FRICTION * time_step
         } else if speed.0 < 0.0 {
-            speed.0 + FRICTION * time.delta_seconds()
+            speed.0 + FRICTION * time_step
         } else {
             0.0
         }
@@ -238,44 +317,53 @@ fn update_car_input(
     speed.0 = speed.0.clamp(-MAX_SPEED + MAX_SPEED / 2.0, MAX_SPEED);
 }
 
-fn car_gas_system(
-    time: Res<Time>,
-    mut query: Query<(&Transform, &Speed, &mut Velocity), With<Car>>,
-) {
-    for (transform, speed, mut velocity) in query.iter_mut() {
-        if speed.0 == 0.0 {
-            let direction = transform.local_y();
-            velocity.linvel = vec2(direction.x, direction.y) * 0.0000001;
-            return;
+fn is_physics_dynamics(config: Res<SimConfig>) -> bool {
+    config.car.dynamics == DynamicsMode::Physics
+}
+
+fn car_gas_system(mut query: Query<(&SensorState, &Transform, &Speed, &mut Velocity), With<Car>>) {
+    for (sensor_state, transform, speed, mut velocity) in query.iter_mut() {
+        if *sensor_state == SensorState::NotReady {
+            velocity.linvel = Vec2::ZERO;
+            continue;
         }
 
-        let translation_delta = transform.local_y() * speed.0;
-        velocity.linvel =
-            vec2(translation_delta.x, translation_delta.y) * 25.0 * time.delta_seconds();
+        let direction = transform.local_y();
+        velocity.linvel = vec2(direction.x, direction.y) * linear_speed(speed.0);
     }
 }
 
 fn car_steer_system(
-    time: Res<Time>,
-    mut query: Query<(&Speed, &TurnSpeed, &mut Velocity), With<Car>>,
+    mut query: Query<(&SensorState, &Speed, &TurnSpeed, &mut Velocity), With<Car>>,
 ) {
-    for (speed, turn_speed, mut velocity) in query.iter_mut() {
-        if speed.0.abs() < MIN_SPEED_TO_STEER {
+    for (sensor_state, speed, turn_speed, mut velocity) in query.iter_mut() {
+        if *sensor_state == SensorState::NotReady || speed.0.abs() < MIN_SPEED_TO_STEER {
             velocity.angvel = 0.0;
-            return;
+            continue;
         }
 
-        velocity.angvel = turn_speed.0 * time.delta_seconds() * TURN_SPEED;
+        // The turning circle widens with speed, a fast car can't turn as tightly
+        let radius = MIN_TURN_RADIUS + speed.0.abs() * TURN_RADIUS_PER_SPEED;
+        let steer = turn_speed.0 / TURN_SPEED;
+        velocity.angvel = steer * linear_speed(speed.0) / radius;
     }
 }
 
+/// Pixels per second the car is pushed at for a `Speed`
+fn linear_speed(speed: f32) -> f32 {
+    speed * 25.0 * SIM_TIMESTEP
+}
+
 fn draw_ray_cast(
-    lines: &mut DebugLines,
+    lines: Option<&mut DebugLines>,
     settings: &Settings,
     start: Vec3,
     end: Vec3,
     color: Color,
 ) {
+    let Some(lines) = lines else {
+        return;
+    };
     if !settings.is_show_rays {
         return;
     }
@@ -288,34 +376,51 @@ fn draw_ray_cast(
 }
 
 fn sensors_system(
-    mut lines: ResMut<DebugLines>,
+    mut lines: Option<ResMut<DebugLines>>,
+    config: Res<SimConfig>,
     settings: Res<Settings>,
     ray_cast_sensors: Res<RayCastSensors>,
     rapier_context: Res<RapierContext>,
-    mut query: Query<(&Transform, &Velocity, &mut Brain, &Speed, &TurnSpeed), With<Car>>,
+    enemy_query: Query<&EnemyType>,
+    mut query: Query<(&Transform, &mut Brain, &mut SensorState), With<Car>>,
 ) {
-    for (transform, velocity, mut brain, speed, turn_speed) in query.iter_mut() {
+    let max_toi = config.sensors.raycast_max_toi;
+    let num_rays = ray_cast_sensors.0.len();
+    let num_hit_classes = if config.sensors.ray_hit_class {
+        num_rays * HitClass::COUNT
+    } else {
+        0
+    };
+    for (transform, mut brain, mut sensor_state) in query.iter_mut() {
         let raycast_filter = CollisionGroups {
             memberships: Group::GROUP_1,
             filters: Group::GROUP_2,
         };
         let filter = QueryFilter::default().groups(raycast_filter);
         let ray_pos = transform.translation;
-        let mut nn_inputs = Vec::new();
+        let brain = &mut *brain;
+        brain.sensor_inputs.clear();
+        brain.sensor_inputs.resize(config.sensors.num_inputs(), 0.0);
+        let (distances, inputs) = brain.sensor_inputs.split_at_mut(num_rays);
+        let (hit_classes, car_inputs) = inputs.split_at_mut(num_hit_classes);
 
         // Ray casts
-        // let rot = velocity.linvel.y.atan2(velocity.linvel.x) - PI / 2.0;
-        let rot = transform.rotation.z;
-        // let rot = turn_speed.0;
-        for (mut x, mut y) in ray_cast_sensors.0.iter() {
+        let rot = heading(transform);
+        for (idx, (mut x, mut y)) in ray_cast_sensors.0.iter().copied().enumerate() {
             (x, y) = rotate_point(x, y, rot);
             let dest_vec = vec2(x, y);
-            let end_point = calculate_endpoint(ray_pos, dest_vec, RAYCAST_MAX_TOI);
-            draw_ray_cast(&mut lines, &settings, ray_pos, end_point, Color::RED);
+            let end_point = calculate_endpoint(ray_pos, dest_vec, max_toi);
+            draw_ray_cast(
+                lines.as_deref_mut(),
+                &settings,
+                ray_pos,
+                end_point,
+                Color::RED,
+            );
 
             let ray_pos_2d = vec2(ray_pos.x, ray_pos.y);
-            if let Some((_, toi)) =
-                rapier_context.cast_ray(ray_pos_2d, dest_vec, RAYCAST_MAX_TOI, false, filter)
+            if let Some((entity, toi)) =
+                rapier_context.cast_ray(ray_pos_2d, dest_vec, max_toi, false, filter)
             {
                 // The first collider hit has the entity `entity` and it hit after
                 // the ray travelled a distance equal to `ray_dir * toi`.
@@ -324,21 +429,86 @@ fn sensors_system(
 
                 // Invalidate when hit length more than max toi
                 let dist_to_hit = ray_pos.distance(hit_point);
-                nn_inputs.push(dist_to_hit as f64 / RAYCAST_MAX_TOI as f64);
-                if dist_to_hit > RAYCAST_MAX_TOI {
+                distances[idx] = dist_to_hit / max_toi;
+                if dist_to_hit > max_toi {
                     continue;
                 }
 
-                draw_ray_cast(&mut lines, &settings, ray_pos, hit_point, Color::GREEN);
+                let hit_class = HitClass::of(enemy_query.get(entity).ok());
+                if let Some(flag) = hit_classes.get_mut(idx * HitClass::COUNT + hit_class as usize)
+                {
+                    *flag = 1.0;
+                }
+
+                draw_ray_cast(
+                    lines.as_deref_mut(),
+                    &settings,
+                    ray_pos,
+                    hit_point,
+                    Color::GREEN,
+                );
             } else {
-                nn_inputs.push(1.0);
+                distances[idx] = 1.0;
             }
         }
 
-        brain.ray_inputs = nn_inputs;
+        let position = vec2(ray_pos.x, ray_pos.y);
+        let displacement = brain
+            .last_position
+            .map_or(Vec2::ZERO, |last_position| position - last_position);
+        brain.last_position = Some(position);
+        fill_car_inputs(&config.sensors, transform, displacement, car_inputs);
+
+        *sensor_state = SensorState::Ready;
     }
 }
 
+/// Speed, heading and lane inputs of the enabled sensors, `displacement`
+/// is how far the car moved since the previous tick
+fn fill_car_inputs(
+    sensors: &SensorConfig,
+    transform: &Transform,
+    displacement: Vec2,
+    inputs: &mut [f32],
+) {
+    let road_width = ROAD_RIGHT_X - ROAD_LEFT_X;
+    let road_center = (ROAD_LEFT_X + ROAD_RIGHT_X) / 2.0;
+    let x = transform.translation.x;
+
+    let mut inputs = inputs.iter_mut();
+    let mut push = |is_enabled: bool, value: f32| {
+        if is_enabled {
+            *inputs.next().expect("Sensor inputs sized by num_inputs") = value;
+        }
+    };
+
+    // Forward speed, negative when reversing
+    let forward = transform.local_y();
+    let speed = displacement.dot(vec2(forward.x, forward.y)) / SIM_TIMESTEP;
+    push(sensors.speed, (speed / SENSOR_MAX_SPEED).clamp(-1.0, 1.0));
+    // The road runs up the y axis, so the yaw is the heading relative to it
+    push(sensors.heading, heading(transform) / PI);
+    push(
+        sensors.lane_offset,
+        ((x - road_center) / (road_width / 2.0)).clamp(-1.0, 1.0),
+    );
+    push(
+        sensors.edge_distances,
+        ((x - ROAD_LEFT_X) / road_width).clamp(0.0, 1.0),
+    );
+    push(
+        sensors.edge_distances,
+        ((ROAD_RIGHT_X - x) / road_width).clamp(0.0, 1.0),
+    );
+}
+
+/// Yaw of the car in radians, 0 while it faces up the road and positive
+/// when turned left, the precomputed sensors are rotated by it
+fn heading(transform: &Transform) -> f32 {
+    let (yaw, _, _) = transform.rotation.to_euler(EulerRot::ZYX);
+    yaw
+}
+
 fn calculate_endpoint(pos: Vec3, direction: Vec2, length: f32) -> Vec3 {
     let dir = direction.normalize();
     vec3(pos[0] + dir[0] * length, pos[1] + dir[1] * length, 0.0)
@@ -361,29 +531,97 @@ fn rotate_point(x: f32, y: f32, angle_rad: f32) -> (f32, f32) {
     (x_prime, y_prime)
 }
 
+impl CarControls {
+    pub fn from_keys(w_key: bool, a_key: bool, s_key: bool, d_key: bool) -> Self {
+        let steer = if a_key {
+            1.0
+        } else if d_key {
+            -1.0
+        } else {
+            0.0
+        };
+
+        Self {
+            throttle: if w_key { 1.0 } else { 0.0 },
+            steer,
+            brake: if s_key { 1.0 } else { 0.0 },
+        }
+    }
+
+    /// `outputs` are throttle, steering and brake, scaled to 0..1 from the
+    /// range of `activation`. Unbounded outputs are used as they are
+    pub fn from_nn_outputs(outputs: &[f32], activation: Activation, scheme: ControlScheme) -> Self {
+        let unit = |o: f32| match activation.bounds() {
+            Some((low, high)) => (o - low) / (high - low),
+            None => o,
+        };
+        let (throttle, steer, brake) = (unit(outputs[0]), unit(outputs[1]), unit(outputs[2]));
+        match scheme {
+            ControlScheme::Discrete => {
+                let w_key = throttle >= NN_W_ACTIVATION_THRESHOLD;
+                let s_key = brake >= NN_S_ACTIVATION_THRESHOLD;
+                let a_key = steer >= 0.5;
+                CarControls::from_keys(w_key, a_key, s_key, !a_key)
+            }
+            ControlScheme::Continuous => Self {
+                throttle: throttle.clamp(0.0, 1.0),
+                steer: ((steer - 0.5) * 2.0).clamp(-1.0, 1.0),
+                brake: brake.clamp(0.0, 1.0),
+            },
+        }
+    }
+}
+
+impl HitClass {
+    pub const COUNT: usize = 4;
+
+    /// Anything that isn't traffic is a road wall, bound control trucks are trucks
+    fn of(enemy_type: Option<&EnemyType>) -> Self {
+        match enemy_type {
+            None => HitClass::Wall,
+            Some(EnemyType::Simple) => HitClass::SlowCar,
+            Some(EnemyType::Horizontal(_)) => HitClass::HorizontalCar,
+            Some(EnemyType::Truck) => HitClass::Truck,
+        }
+    }
+}
+
 impl CarBundle {
-    pub fn new(asset_server: &AssetServer) -> Self {
-        let mut rng = rand::thread_rng();
-        let rand_x = rng.gen_range(800.0..1100.0);
+    pub fn new(asset_server: Option<&AssetServer>, config: &SimConfig, rng: &mut SimRng) -> Self {
+        let brain = Genome::new(config, &mut rng.mutation);
+        CarBundle::with_brain(asset_server, config, &brain, rng)
+    }
+
+    pub fn with_brain(
+        asset_server: Option<&AssetServer>,
+        config: &SimConfig,
+        brain: &Genome,
+        rng: &mut SimRng,
+    ) -> Self {
+        let rand_x = rng.spawning.gen_range(800.0..1100.0);
+        // Physics cars get their velocity set every tick, damping would only eat into it
+        let damping = match config.car.dynamics {
+            DynamicsMode::Position => 100.0,
+            DynamicsMode::Physics => 0.0,
+        };
 
         Self {
             sprite_bundle: SpriteBundle {
                 transform: Transform::from_xyz(rand_x, WINDOW_HEIGHT / 2.0, 0.0)
                     .with_scale(vec3(2.5, 2.5, 1.0)),
-                texture: asset_server.load("agent.png"),
+                texture: load_texture(asset_server, "agent.png"),
                 ..default()
             },
             car: Car,
             fitness: Fitness(0.0),
             brain: Brain {
-                nn: Net::new(vec![
-                    NUM_RAY_CASTS as usize,
-                    NUM_HIDDEN_NODES,
-                    NUM_OUPUT_NODES,
-                ]),
-                ray_inputs: Vec::new(),
-                nn_outputs: Vec::new(),
+                nn: brain.clone(),
+                sensor_inputs: Vec::new(),
+                last_position: None,
+                activations: Activations::default(),
+                memory: brain.initial_memory(),
             },
+            sensor_state: SensorState::NotReady,
             turn_speed: TurnSpeed(0.0),
             speed: Speed(0.0),
             velocity: Velocity::zero(),
@@ -392,8 +630,8 @@ impl CarBundle {
             collider: Collider::cuboid(5.0, 8.0),
             events: ActiveEvents::COLLISION_EVENTS,
             damping: Damping {
-                angular_damping: 100.0,
-                linear_damping: 100.0,
+                angular_damping: damping,
+                linear_damping: damping,
             },
             sleep: Sleeping::disabled(),
             ccd: Ccd::enabled(),
@@ -403,10 +641,248 @@ impl CarBundle {
             },
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
 
-    pub fn with_brain(asset_server: &AssetServer, brain: &Net) -> Self {
-        let mut car = CarBundle::new(asset_server);
-        car.brain.nn = brain.clone();
-        car
+    fn assert_close(found: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (found.0 - expected.0).abs() < EPSILON && (found.1 - expected.1).abs() < EPSILON,
+            "expected {:?}, found {:?}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn rotate_point_turns_counter_clockwise() {
+        assert_close(rotate_point(1.0, 0.0, 0.0), (1.0, 0.0));
+        assert_close(rotate_point(1.0, 0.0, PI / 2.0), (0.0, 1.0));
+        assert_close(rotate_point(0.0, 1.0, PI / 2.0), (-1.0, 0.0));
+        assert_close(rotate_point(0.0, 1.0, -PI / 2.0), (1.0, 0.0));
+        assert_close(rotate_point(2.0, 0.0, PI), (-2.0, 0.0));
+    }
+
+    #[test]
+    fn calculate_endpoint_is_length_away_along_direction() {
+        let pos = vec3(10.0, -5.0, 3.0);
+        let end = calculate_endpoint(pos, vec2(0.0, 2.0), 100.0);
+        assert_close((end.x, end.y), (10.0, 95.0));
+        assert_eq!(end.z, 0.0);
+
+        let end = calculate_endpoint(pos, vec2(1.0, 1.0), 2.0_f32.sqrt());
+        assert_close((end.x, end.y), (11.0, -4.0));
+    }
+
+    #[test]
+    fn heading_is_the_yaw_angle() {
+        for angle in [0.0, 0.3, PI / 2.0, -PI / 2.0, 2.5, -2.5] {
+            let transform = Transform::from_rotation(Quat::from_rotation_z(angle));
+            assert!((heading(&transform) - angle).abs() < EPSILON);
+        }
+
+        // The raw quaternion component is only the sine of half the angle
+        let transform = Transform::from_rotation(Quat::from_rotation_z(PI / 2.0));
+        assert!((heading(&transform) - transform.rotation.z).abs() > 0.5);
+    }
+
+    #[test]
+    fn rays_follow_the_car_heading() {
+        let pos = vec3(0.0, 0.0, 0.0);
+        // Sensors pointing straight ahead and 45 degrees to the right
+        let sensors = [(0.0, 1.0), (1.0, 1.0)];
+        let cases = [
+            (0.0, [(0.0, 10.0), (7.071068, 7.071068)]),
+            (PI / 2.0, [(-10.0, 0.0), (-7.071068, 7.071068)]),
+            (PI, [(0.0, -10.0), (-7.071068, -7.071068)]),
+            (-PI / 2.0, [(10.0, 0.0), (7.071068, -7.071068)]),
+        ];
+
+        for (angle, expected) in cases {
+            let mut transform = Transform::from_translation(pos);
+            transform.rotate_z(angle);
+            // The car's forward is the straight ahead ray
+            let forward = transform.local_y();
+
+            let rot = heading(&transform);
+            for ((x, y), expected) in sensors.iter().zip(expected) {
+                let (x, y) = rotate_point(*x, *y, rot);
+                let end = calculate_endpoint(pos, vec2(x, y), 10.0);
+                assert_close((end.x, end.y), expected);
+            }
+
+            let (x, y) = rotate_point(sensors[0].0, sensors[0].1, rot);
+            assert_close((x, y), (forward.x, forward.y));
+        }
+    }
+
+    fn spawn_car(world: &mut World, config: &SimConfig, seed: u64, is_ready: bool) -> Entity {
+        let nn = Genome::new(config, &mut StdRng::seed_from_u64(seed));
+        let (sensor_inputs, sensor_state) = if is_ready {
+            let num_inputs = config.sensors.num_inputs();
+            (vec![0.5; num_inputs], SensorState::Ready)
+        } else {
+            (Vec::new(), SensorState::NotReady)
+        };
+
+        world
+            .spawn((
+                Car,
+                Brain {
+                    memory: nn.initial_memory(),
+                    nn,
+                    activations: Activations::default(),
+                    sensor_inputs,
+                    last_position: None,
+                },
+                sensor_state,
+                Speed(500.0),
+                TurnSpeed(TURN_SPEED),
+                Transform::default(),
+                Velocity::zero(),
+            ))
+            .id()
+    }
+
+    /// Not ready cars first, so that one stopping the loop would leave the rest standing
+    fn spawn_mixed_cars(world: &mut World, config: &SimConfig) -> Vec<(Entity, bool)> {
+        [false, true, false, true, true]
+            .into_iter()
+            .enumerate()
+            .map(|(i, is_ready)| (spawn_car(world, config, i as u64, is_ready), is_ready))
+            .collect()
+    }
+
+    #[test]
+    fn continuous_controls_follow_the_output_range() {
+        let sigmoid = CarControls::from_nn_outputs(
+            &[1.0, 0.5, 0.0],
+            Activation::Sigmoid,
+            ControlScheme::Continuous,
+        );
+        let tanh = CarControls::from_nn_outputs(
+            &[1.0, 0.0, -1.0],
+            Activation::Tanh,
+            ControlScheme::Continuous,
+        );
+        for controls in [sigmoid, tanh] {
+            assert_close((controls.throttle, controls.brake), (1.0, 0.0));
+            assert!(controls.steer.abs() < EPSILON);
+        }
+
+        let mut config = SimConfig::default();
+        config.car.controls = ControlScheme::Continuous;
+        config.brain.output_activation = Activation::Relu;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn continuous_throttle_outweighing_brake_speeds_up() {
+        // Sigmoid outputs never reach 0, the brake is always a little pressed
+        let controls = CarControls::from_nn_outputs(
+            &[0.9, 0.5, 0.1],
+            Activation::Sigmoid,
+            ControlScheme::Continuous,
+        );
+        let mut turn_speed = TurnSpeed(0.0);
+        let mut speed = Speed(0.0);
+        let mut last_speed = speed.0;
+        for _ in 0..10 {
+            update_car_input(&controls, &mut turn_speed, &mut speed);
+            assert!(speed.0 > last_speed, "speed stuck at {}", speed.0);
+            last_speed = speed.0;
+        }
+
+        let braking = CarControls::from_nn_outputs(
+            &[0.5, 0.5, 0.5],
+            Activation::Sigmoid,
+            ControlScheme::Continuous,
+        );
+        for _ in 0..10 {
+            update_car_input(&braking, &mut turn_speed, &mut speed);
+        }
+        assert_eq!(speed.0, 0.0);
+    }
+
+    #[test]
+    fn every_ready_car_is_driven() {
+        let mut config = SimConfig::default();
+        // Sigmoid outputs always press some throttle and never a full brake
+        config.car.controls = ControlScheme::Continuous;
+        let mut world = World::new();
+        let cars = spawn_mixed_cars(&mut world, &config);
+        world.insert_resource(config);
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(car_nn_controlled_system);
+        schedule.run(&mut world);
+
+        for (entity, is_ready) in cars {
+            let translation = world.get::<Transform>(entity).unwrap().translation;
+            let speed = world.get::<Speed>(entity).unwrap().0;
+            if is_ready {
+                assert!(translation.y > 0.0, "ready car {:?} didn't move", entity);
+            } else {
+                assert_eq!(translation, Vec3::ZERO);
+                assert_eq!(speed, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn every_ready_car_gets_a_velocity() {
+        let mut world = World::new();
+        let cars = spawn_mixed_cars(&mut world, &SimConfig::default());
+
+        let mut schedule = Schedule::new();
+        schedule.add_systems((car_gas_system, car_steer_system));
+        schedule.run(&mut world);
+
+        for (entity, is_ready) in cars {
+            let velocity = world.get::<Velocity>(entity).unwrap();
+            if is_ready {
+                assert!(
+                    velocity.linvel.y > 0.0,
+                    "ready car {:?} has no speed",
+                    entity
+                );
+                assert!(
+                    velocity.angvel > 0.0,
+                    "ready car {:?} isn't turning",
+                    entity
+                );
+            } else {
+                assert_eq!(velocity.linvel, Vec2::ZERO);
+                assert_eq!(velocity.angvel, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn brain_rejecting_its_inputs_leaves_the_generation() {
+        let config = SimConfig::default();
+        let mut world = World::new();
+        let cars = spawn_mixed_cars(&mut world, &config);
+        let (bad_car, _) = cars[1];
+        world
+            .get_mut::<Brain>(bad_car)
+            .unwrap()
+            .sensor_inputs
+            .push(0.5);
+        world.insert_resource(config);
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(car_nn_controlled_system);
+        schedule.run(&mut world);
+
+        for (entity, _) in cars {
+            assert_eq!(world.get::<Car>(entity).is_some(), entity != bad_car);
+        }
     }
 }
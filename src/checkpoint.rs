@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::car::{Brain, Car};
+use crate::genome::Genome;
+use crate::simulation::{SimSet, SimulationTick};
+use crate::species::SpeciesTracker;
+use crate::*;
+
+/// Version of the on-disk checkpoint format, bump it whenever `Checkpoint` changes
+pub const CHECKPOINT_FORMAT_VERSION: u32 = 4;
+const LATEST_CHECKPOINT_FILE: &str = "latest.ron";
+/// Brain of the fittest car of the run so far, loadable with `--brain`
+const BEST_BRAIN_FILE: &str = "best.ron";
+
+pub struct CheckpointPlugin;
+
+/// Checkpoint loaded before the app starts, the population is spawned from it
+#[derive(Resource, Default)]
+pub struct ResumeCheckpoint(pub Option<Checkpoint>);
+
+/// Snapshot of a training run taken right after a generation is bred
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub generation_count: u32,
+    pub fitness: Vec<f32>,
+    pub brains: Vec<Genome>,
+    pub adaptive_mutation: AdaptiveMutation,
+    pub species: SpeciesTracker,
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Truncated,
+    Malformed(String),
+    UnsupportedVersion(u32),
+}
+
+impl Plugin for CheckpointPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ResumeCheckpoint>().add_systems(
+            (checkpoint_system, stats_file_system)
+                .in_set(SimSet::Report)
+                .in_schedule(SimulationTick),
+        );
+    }
+}
+
+/// The brain or checkpoint to start from, if any. Files asked for
+/// explicitly must load, a missing latest checkpoint means a new run
+pub fn load_resume_checkpoint(
+    config: &SimConfig,
+    rng: &mut impl Rng,
+) -> Result<Option<Checkpoint>, String> {
+    let checkpoint = if let Some(path) = &config.brain.load_from {
+        let brain = Genome::load(path)
+            .map_err(|e| format!("Failed to load brain {}: {}", path.display(), e))?;
+        println!("Spawning the population from brain {}", path.display());
+
+        let mut brains = vec![brain; config.num_ai_cars as usize];
+        if let Some(noise) = &config.brain.load_noise {
+            for brain in brains.iter_mut().skip(1) {
+                brain.mutate_with(noise, rng);
+            }
+        }
+
+        Checkpoint {
+            version: CHECKPOINT_FORMAT_VERSION,
+            generation_count: 0,
+            fitness: Vec::new(),
+            brains,
+            adaptive_mutation: AdaptiveMutation::new(config.evolution.mutation.sigma),
+            species: SpeciesTracker::new(config.evolution.speciation.compatibility_threshold),
+        }
+    } else {
+        let settings = &config.checkpoint;
+        let path = match &settings.resume_from {
+            Some(path) => path.clone(),
+            None => {
+                let path = settings.dir.join(LATEST_CHECKPOINT_FILE);
+                if !settings.resume || !path.exists() {
+                    return Ok(None);
+                }
+                path
+            }
+        };
+
+        let checkpoint = Checkpoint::load(&path)
+            .map_err(|e| format!("Failed to resume from {}: {}", path.display(), e))?;
+        println!(
+            "Resuming from generation {} ({})",
+            checkpoint.generation_count,
+            path.display()
+        );
+        checkpoint
+    };
+
+    if let Some(e) = checkpoint
+        .brains
+        .iter()
+        .find_map(|b| b.check_config(config).err())
+    {
+        return Err(e);
+    }
+
+    Ok(Some(checkpoint))
+}
+
+fn checkpoint_system(
+    config: Res<SimConfig>,
+    sim_stats: Res<SimStats>,
+    adaptive_mutation: Res<AdaptiveMutation>,
+    species_tracker: Res<SpeciesTracker>,
+    mut last_saved_generation: Local<u32>,
+    brains_query: Query<&Brain, With<Car>>,
+) {
+    let settings = &config.checkpoint;
+    let generation = sim_stats.generation_count;
+    if settings.every_n_generations == 0
+        || generation == 0
+        || generation == *last_saved_generation
+        || !generation.is_multiple_of(settings.every_n_generations)
+    {
+        return;
+    }
+
+    // Wait for the freshly bred generation to be spawned, on its
+    // first tick none of its cars have crashed yet
+    if brains_query.iter().len() != config.num_ai_cars as usize {
+        return;
+    }
+    *last_saved_generation = generation;
+
+    let checkpoint = Checkpoint {
+        version: CHECKPOINT_FORMAT_VERSION,
+        generation_count: generation,
+        fitness: sim_stats.fitness.clone(),
+        brains: brains_query.iter().map(|b| b.nn.clone()).collect(),
+        adaptive_mutation: adaptive_mutation.clone(),
+        species: species_tracker.clone(),
+    };
+    let path = settings.dir.join(format!("gen-{:05}.ron", generation));
+    let result = checkpoint.save(&path).and_then(|_| {
+        fs::copy(&path, settings.dir.join(LATEST_CHECKPOINT_FILE))
+            .map(|_| ())
+            .map_err(CheckpointError::Io)
+    });
+
+    match result {
+        Ok(_) => info!("Saved checkpoint {}", path.display()),
+        Err(e) => error!("Failed to save checkpoint {}: {}", path.display(), e),
+    }
+
+    if let Some(brain) = &sim_stats.best_brain {
+        let path = settings.dir.join(BEST_BRAIN_FILE);
+        if let Err(e) = brain.save(&path) {
+            error!("Failed to save best brain {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn stats_file_system(
+    config: Res<SimConfig>,
+    sim_stats: Res<SimStats>,
+    mut last_written_generation: Local<Option<u32>>,
+) {
+    let Some(path) = &config.stats_file else {
+        return;
+    };
+
+    // The generation the run starts from has no stats yet,
+    // or had them written before the run was checkpointed
+    let generation = sim_stats.generation_count;
+    if *last_written_generation.get_or_insert(generation) == generation {
+        return;
+    }
+    let Some(max_fitness) = sim_stats.fitness.last() else {
+        return;
+    };
+    *last_written_generation = Some(generation);
+
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            let is_new_file = !path.exists();
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            if is_new_file {
+                writeln!(file, "generation,max_fitness")?;
+            }
+            writeln!(file, "{},{}", generation, max_fitness)
+        });
+
+    if let Err(e) = result {
+        error!("Failed to write stats to {}: {}", path.display(), e);
+    }
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CheckpointError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(CheckpointError::Io)?;
+        }
+
+        let contents =
+            ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default().depth_limit(4))
+                .map_err(|e| CheckpointError::Malformed(e.to_string()))?;
+
+        fs::write(path, contents).map_err(CheckpointError::Io)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CheckpointError> {
+        let contents = fs::read_to_string(path).map_err(CheckpointError::Io)?;
+        let checkpoint: Checkpoint = ron::from_str(&contents).map_err(|e| match e.code {
+            ron::Error::Eof => CheckpointError::Truncated,
+            _ => CheckpointError::Malformed(e.to_string()),
+        })?;
+
+        if checkpoint.version != CHECKPOINT_FORMAT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(checkpoint.version));
+        }
+
+        Ok(checkpoint)
+    }
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "Checkpoint io error: {}", e),
+            CheckpointError::Truncated => write!(f, "Checkpoint file is truncated"),
+            CheckpointError::Malformed(e) => write!(f, "Malformed checkpoint file: {}", e),
+            CheckpointError::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported checkpoint format version {}, expected {}",
+                v, CHECKPOINT_FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
@@ -1,34 +1,432 @@
-use bevy::prelude::Color;
+use std::{fmt, fs, io, path::Path, path::PathBuf};
+
+use bevy::prelude::{Color, Resource};
+use serde::{Deserialize, Serialize};
+
+use crate::car::{ControlScheme, DynamicsMode, HitClass};
+use crate::genome::BrainKind;
+use crate::neat::NeatParams;
+use crate::nn::{Activation, CrossoverKind, InitParams, MutationParams};
+use crate::selection::SelectionStrategy;
 
 /// Main
 pub const NUM_ROAD_TILES: u32 = 20;
 pub const ROAD_SPRITE_W: f32 = 160.0;
 pub const ROAD_SPRITE_H: f32 = 288.0;
-pub const NUM_ENEMY_CARS: u32 = 140;
 pub const SPRITE_SCALE_FACTOR: f32 = 6.0;
 pub const BACKGROUND_COLOR: Color = Color::BLACK;
 pub const WINDOW_WIDTH: f32 = 1980.0;
 pub const WINDOW_HEIGHT: f32 = 1080.0;
+pub const SIM_TIMESTEP: f32 = 1.0 / 60.0;
+/// The road runs up the y axis between its walls
+pub const ROAD_LEFT_X: f32 = ROAD_SPRITE_W / 2.0 * SPRITE_SCALE_FACTOR + 238.0;
+pub const ROAD_RIGHT_X: f32 = ROAD_SPRITE_W * SPRITE_SCALE_FACTOR + 248.0;
 
 /// Car
-pub const NUM_AI_CARS: u32 = 100;
 pub const TURN_SPEED: f32 = 25.0;
 pub const CAR_THRUST: f32 = 5.0 * 100.0;
 pub const MAX_SPEED: f32 = 10.0 * 300.0;
 pub const FRICTION: f32 = 30.0 * 100.0;
 pub const MIN_SPEED_TO_STEER: f32 = 50.0;
-pub const NUM_RAY_CASTS: u32 = 15;
-pub const RAYCAST_SPREAD_ANGLE_DEG: f32 = 130.0;
-pub const RAYCAST_START_ANGLE_DEG: f32 = 20.0;
-pub const RAYCAST_MAX_TOI: f32 = 200.0;
+/// Physics dynamics, the turning radius in pixels at a standstill and its growth per unit of speed
+pub const MIN_TURN_RADIUS: f32 = 40.0;
+pub const TURN_RADIUS_PER_SPEED: f32 = 0.1;
+/// Forward speed in pixels per second that the speed sensor reads as 1,
+/// the velocity physics dynamics gives an undamped car at `MAX_SPEED`
+pub const SENSOR_MAX_SPEED: f32 = 1250.0;
 // pub const RAYCAST_THICKNESS: f32 = 0.3;
 
 /// NN
-pub const NUM_HIDDEN_NODES: usize = 15;
 pub const NUM_OUPUT_NODES: usize = 3;
 pub const NN_VIZ_NODE_RADIUS: f32 = 10.0;
-pub const NN_W_ACTIVATION_THRESHOLD: f64 = 0.3;
-pub const NN_S_ACTIVATION_THRESHOLD: f64 = 0.8;
+pub const NN_VIZ_MAX_NODE_SPACING: f32 = 200.0;
+pub const NN_W_ACTIVATION_THRESHOLD: f32 = 0.3;
+pub const NN_S_ACTIVATION_THRESHOLD: f32 = 0.8;
 
 /// Others
 pub const FONT_RES_PATH: &str = "Magero.ttf";
+pub const CONFIG_PATH: &str = "config.ron";
+
+/// Runtime tunables, loaded from `CONFIG_PATH` at startup.
+/// Missing fields fall back to their defaults
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    pub num_ai_cars: u32,
+    pub num_enemy_cars: u32,
+    pub seed: Option<u64>,
+    /// Per generation stats are appended to this csv file
+    pub stats_file: Option<PathBuf>,
+    pub sensors: SensorConfig,
+    pub car: CarConfig,
+    pub brain: BrainConfig,
+    pub evolution: EvolutionConfig,
+    pub checkpoint: CheckpointConfig,
+    pub headless: HeadlessConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorConfig {
+    pub num_ray_casts: u32,
+    pub raycast_spread_angle_deg: f32,
+    pub raycast_start_angle_deg: f32,
+    pub raycast_max_toi: f32,
+    /// Optional brain inputs on top of the ray distances
+    pub speed: bool,
+    pub heading: bool,
+    pub lane_offset: bool,
+    pub edge_distances: bool,
+    pub ray_hit_class: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CarConfig {
+    pub controls: ControlScheme,
+    pub dynamics: DynamicsMode,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrainConfig {
+    pub kind: BrainKind,
+    /// Dense brains only, NEAT brains grow their own hidden nodes
+    pub num_hidden_nodes: usize,
+    pub hidden_activation: Activation,
+    pub output_activation: Activation,
+    /// Dense brains only, hidden layers remember their outputs from the previous tick
+    pub recurrent: bool,
+    /// How the weights of new brains are drawn
+    pub init: InitParams,
+    /// Spawn the first generation from this saved brain
+    pub load_from: Option<PathBuf>,
+    /// Mutation applied to all but the first copy of `load_from`, none spawns identical copies
+    pub load_noise: Option<MutationParams>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvolutionConfig {
+    /// How parents are picked from the previous generation
+    pub selection: SelectionStrategy,
+    /// Best brains copied unchanged into the next generation
+    pub elite_count: u32,
+    /// Chance a child has two parents instead of being a copy of one
+    pub crossover_rate: f32,
+    pub crossover_kind: CrossoverKind,
+    pub mutation: MutationParams,
+    pub adaptive_mutation: AdaptiveMutationConfig,
+    pub speciation: SpeciationConfig,
+    /// NEAT brains only
+    pub neat: NeatParams,
+}
+
+/// Raises the mutation sigma while the best fitness is stuck and
+/// lowers it back to `mutation.sigma` once it improves again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveMutationConfig {
+    pub enabled: bool,
+    /// Generations without a new best fitness before sigma is raised
+    pub stagnation_generations: u32,
+    /// Sigma is multiplied by this per stagnant generation, divided once progress resumes
+    pub factor: f32,
+    pub max_sigma: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeciationConfig {
+    pub enabled: bool,
+    /// Brains closer than this, in mean weight difference, share a species
+    pub compatibility_threshold: f32,
+    /// Threshold is adjusted each generation to get this many species, 0 keeps it fixed
+    pub target_species: u32,
+    /// Generations without improvement before a species is removed
+    pub stagnation_generations: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointConfig {
+    pub dir: PathBuf,
+    /// 0 disables checkpoints
+    pub every_n_generations: u32,
+    /// Resume from the latest checkpoint in `dir`. Off by default, as a resumed
+    /// run doesn't repeat the random streams of a fresh run with the same seed
+    pub resume: bool,
+    /// Resume from this checkpoint file instead of the latest one
+    pub resume_from: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadlessConfig {
+    pub max_generations: u32,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl SimConfig {
+    /// Loads the config at `path`, a missing file gives the default config
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Self::load(path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: SimConfig =
+            ron::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.num_ai_cars < 1 {
+            return invalid("num_ai_cars must be at least 1");
+        }
+        if self.sensors.num_ray_casts < 2 {
+            return invalid("sensors.num_ray_casts must be at least 2");
+        }
+        if self.sensors.raycast_spread_angle_deg.is_nan()
+            || self.sensors.raycast_spread_angle_deg <= 0.0
+        {
+            return invalid("sensors.raycast_spread_angle_deg must be positive");
+        }
+        if !self.sensors.raycast_start_angle_deg.is_finite() {
+            return invalid("sensors.raycast_start_angle_deg must be a number");
+        }
+        if self.sensors.raycast_max_toi.is_nan() || self.sensors.raycast_max_toi <= 0.0 {
+            return invalid("sensors.raycast_max_toi must be positive");
+        }
+        if self.brain.num_hidden_nodes < 2 {
+            return invalid("brain.num_hidden_nodes must be at least 2");
+        }
+        if self.car.controls == ControlScheme::Continuous
+            && self.brain.output_activation.bounds().is_none()
+        {
+            return invalid(
+                "car.controls Continuous needs a bounded brain.output_activation, Sigmoid, Tanh or Softsign",
+            );
+        }
+        if self.brain.recurrent && self.brain.kind != BrainKind::Dense {
+            return invalid("brain.recurrent is only supported by Dense brains");
+        }
+        if let Err(e) = self.brain.init.validate() {
+            return invalid(&format!("brain.init: {}", e));
+        }
+        if let Some(Err(e)) = self.brain.load_noise.as_ref().map(|n| n.validate()) {
+            return invalid(&format!("brain.load_noise: {}", e));
+        }
+        if let Err(e) = self.evolution.selection.validate() {
+            return invalid(&format!("evolution.selection: {}", e));
+        }
+        if self.evolution.elite_count > self.num_ai_cars {
+            return invalid("evolution.elite_count can't exceed num_ai_cars");
+        }
+        if !(0.0..=1.0).contains(&self.evolution.crossover_rate) {
+            return invalid("evolution.crossover_rate must be between 0 and 1");
+        }
+        if let Err(e) = self.evolution.mutation.validate() {
+            return invalid(&format!("evolution.mutation: {}", e));
+        }
+        let adaptive = &self.evolution.adaptive_mutation;
+        if adaptive.stagnation_generations < 1 {
+            return invalid(
+                "evolution.adaptive_mutation.stagnation_generations must be at least 1",
+            );
+        }
+        if adaptive.factor.is_nan() || adaptive.factor <= 1.0 {
+            return invalid("evolution.adaptive_mutation.factor must be greater than 1");
+        }
+        if adaptive.max_sigma.is_nan() || adaptive.max_sigma < self.evolution.mutation.sigma {
+            return invalid("evolution.adaptive_mutation.max_sigma can't be below mutation.sigma");
+        }
+        let speciation = &self.evolution.speciation;
+        if !(speciation.compatibility_threshold > 0.0
+            && speciation.compatibility_threshold.is_finite())
+        {
+            return invalid("evolution.speciation.compatibility_threshold must be positive");
+        }
+        if speciation.stagnation_generations < 1 {
+            return invalid("evolution.speciation.stagnation_generations must be at least 1");
+        }
+        if let Err(e) = self.evolution.neat.validate() {
+            return invalid(&format!("evolution.neat: {}", e));
+        }
+        if self.headless.max_generations < 1 {
+            return invalid("headless.max_generations must be at least 1");
+        }
+
+        Ok(())
+    }
+
+    pub fn brain_layer_sizes(&self) -> Vec<usize> {
+        vec![
+            self.sensors.num_inputs(),
+            self.brain.num_hidden_nodes,
+            NUM_OUPUT_NODES,
+        ]
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            num_ai_cars: 100,
+            num_enemy_cars: 140,
+            seed: None,
+            stats_file: None,
+            sensors: SensorConfig::default(),
+            car: CarConfig::default(),
+            brain: BrainConfig::default(),
+            evolution: EvolutionConfig::default(),
+            checkpoint: CheckpointConfig::default(),
+            headless: HeadlessConfig::default(),
+        }
+    }
+}
+
+impl SensorConfig {
+    /// Brain inputs for the enabled sensors. They are laid out as the ray
+    /// distances, the one hot hit class of every ray, then the speed,
+    /// heading, lane offset and the left and right edge distances
+    pub fn num_inputs(&self) -> usize {
+        let num_rays = self.num_ray_casts as usize;
+        let mut num_inputs = num_rays;
+        if self.ray_hit_class {
+            num_inputs += num_rays * HitClass::COUNT;
+        }
+        num_inputs += [self.speed, self.heading, self.lane_offset]
+            .iter()
+            .filter(|&&enabled| enabled)
+            .count();
+        if self.edge_distances {
+            num_inputs += 2;
+        }
+
+        num_inputs
+    }
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            num_ray_casts: 15,
+            raycast_spread_angle_deg: 130.0,
+            raycast_start_angle_deg: 20.0,
+            raycast_max_toi: 200.0,
+            speed: false,
+            heading: false,
+            lane_offset: false,
+            edge_distances: false,
+            ray_hit_class: false,
+        }
+    }
+}
+
+impl Default for CarConfig {
+    fn default() -> Self {
+        Self {
+            controls: ControlScheme::Discrete,
+            dynamics: DynamicsMode::Position,
+        }
+    }
+}
+
+impl Default for BrainConfig {
+    fn default() -> Self {
+        Self {
+            kind: BrainKind::Dense,
+            num_hidden_nodes: 15,
+            hidden_activation: Activation::Sigmoid,
+            output_activation: Activation::Sigmoid,
+            recurrent: false,
+            init: InitParams::default(),
+            load_from: None,
+            load_noise: None,
+        }
+    }
+}
+
+impl Default for EvolutionConfig {
+    fn default() -> Self {
+        Self {
+            selection: SelectionStrategy::Roulette,
+            elite_count: 2,
+            crossover_rate: 0.0,
+            crossover_kind: CrossoverKind::Uniform,
+            mutation: MutationParams::default(),
+            adaptive_mutation: AdaptiveMutationConfig::default(),
+            speciation: SpeciationConfig::default(),
+            neat: NeatParams::default(),
+        }
+    }
+}
+
+impl Default for AdaptiveMutationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stagnation_generations: 10,
+            factor: 1.5,
+            max_sigma: 2.0,
+        }
+    }
+}
+
+impl Default for SpeciationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            compatibility_threshold: 0.5,
+            target_species: 8,
+            stagnation_generations: 15,
+        }
+    }
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("checkpoints"),
+            every_n_generations: 10,
+            resume: false,
+            resume_from: None,
+        }
+    }
+}
+
+impl Default for HeadlessConfig {
+    fn default() -> Self {
+        Self {
+            max_generations: 100,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Config io error: {}", e),
+            ConfigError::Parse(e) => write!(f, "Failed to parse config: {}", e),
+            ConfigError::Invalid(e) => write!(f, "Invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
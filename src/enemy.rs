@@ -3,8 +3,9 @@ use bevy::{
     prelude::*,
 };
 use bevy_rapier2d::prelude::*;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
+use crate::simulation::{SimSet, SimulationTick};
 use crate::*;
 
 pub struct EnemyPlugin;
@@ -26,14 +27,22 @@ pub struct BoundControlTruck;
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup)
-            .add_system(update_enemies)
-            .add_system(bound_control_system);
+        app.add_startup_system(setup).add_systems(
+            (update_enemies, bound_control_system)
+                .in_set(SimSet::Act)
+                .in_schedule(SimulationTick),
+        );
     }
 }
 
-fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
-    spawn_enemies(&mut commands, &asset_server);
+fn setup(
+    mut commands: Commands,
+    config: Res<SimConfig>,
+    mut rng: ResMut<SimRng>,
+    asset_server: Option<Res<AssetServer>>,
+) {
+    spawn_enemies(&mut commands, asset_server.as_deref(), &config, &mut rng);
+    spawn_bound_trucks(&mut commands, asset_server.as_deref());
 }
 
 fn update_enemies(
@@ -72,14 +81,20 @@ fn update_enemies(
 
 fn bound_control_system(mut query: Query<&mut Transform, With<BoundControlTruck>>) {
     for mut transform in query.iter_mut() {
-        transform.translation.y += 1.0;
+        transform.translation.y += 60.0 * SIM_TIMESTEP;
     }
 }
 
-pub fn spawn_enemies(commands: &mut Commands, asset_server: &AssetServer) {
+pub fn spawn_enemies(
+    commands: &mut Commands,
+    asset_server: Option<&AssetServer>,
+    config: &SimConfig,
+    rng: &mut SimRng,
+) {
+    let rng = &mut rng.traffic;
     let mut enemy_y = 800.0;
-    for _ in 0..NUM_ENEMY_CARS {
-        let enemy_type = EnemyType::random();
+    for _ in 0..config.num_enemy_cars {
+        let enemy_type = EnemyType::random(rng);
         let enemy_scale = match enemy_type {
             EnemyType::Truck => 3.0,
             _ => 2.5,
@@ -88,7 +103,6 @@ pub fn spawn_enemies(commands: &mut Commands, asset_server: &AssetServer) {
             EnemyType::Truck => Collider::cuboid(6.0, 15.0),
             _ => Collider::cuboid(4.0, 8.0),
         };
-        let mut rng = rand::thread_rng();
         let x = rng.gen_range(743.0..1169.0);
         let y = enemy_y;
         enemy_y += 200.0;
@@ -99,7 +113,7 @@ pub fn spawn_enemies(commands: &mut Commands, asset_server: &AssetServer) {
                     enemy_scale,
                     1.0,
                 )),
-                texture: asset_server.load(enemy_type.get_sprite()),
+                texture: load_texture(asset_server, enemy_type.get_sprite(rng)),
                 ..default()
             },
             RigidBody::Dynamic,
@@ -118,7 +132,7 @@ pub fn spawn_enemies(commands: &mut Commands, asset_server: &AssetServer) {
     }
 }
 
-pub fn spawn_bound_trucks(commands: &mut Commands, asset_server: &AssetServer) {
+pub fn spawn_bound_trucks(commands: &mut Commands, asset_server: Option<&AssetServer>) {
     // Bound control trucks
     let enemy_y = 100.0;
     let mut enemy_x = 743.0; // upto 1169.0
@@ -140,7 +154,7 @@ pub fn spawn_bound_trucks(commands: &mut Commands, asset_server: &AssetServer) {
                     enemy_scale,
                     1.0,
                 )),
-                texture: asset_server.load("bound-truck.png"),
+                texture: load_texture(asset_server, "bound-truck.png"),
                 ..default()
             },
             RigidBody::Fixed,
@@ -157,16 +171,14 @@ pub fn spawn_bound_trucks(commands: &mut Commands, asset_server: &AssetServer) {
 }
 
 impl EnemyType {
-    fn random() -> Self {
+    fn random(rng: &mut impl Rng) -> Self {
         let all_vals = [Self::Horizontal(3.0), Self::Simple, Self::Truck];
-        let mut rng = thread_rng();
         let index = rng.gen_range(0..all_vals.len());
 
         all_vals[index].clone()
     }
 
-    fn get_sprite(&self) -> &str {
-        let mut rng = thread_rng();
+    fn get_sprite(&self, rng: &mut impl Rng) -> &str {
         match self {
             EnemyType::Simple => {
                 let choices = ["enemy-blue-1.png", "enemy-yellow-1.png"];
@@ -0,0 +1,231 @@
+use std::path::Path;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::car::ControlScheme;
+use crate::neat::{Innovations, NeatNet, NeatParams};
+use crate::nn::{
+    Activation, Activations, BrainFileError, CrossoverKind, Memory, MutationParams, Net, NetError,
+};
+use crate::*;
+
+/// Which kind of net the population evolves
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BrainKind {
+    /// Fixed `[inputs, hidden, outputs]` layers, only the weights evolve
+    Dense,
+    /// NEAT genome, nodes and connections are added over the generations
+    Neat,
+}
+
+/// A car brain, either kind of net behind the same predict contract.
+/// Brain files keep each net's own format, see `save` and `load`. Checkpoints
+/// tag the kind, ron drops the activation names an untagged enum would need
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Genome {
+    Dense(Net),
+    Neat(NeatNet),
+}
+
+/// Brain laid out in columns for drawing, inputs first and outputs last
+pub struct BrainGraph {
+    /// Node values per column
+    pub columns: Vec<Vec<f32>>,
+    /// Enabled connections, as (column, row) pairs
+    pub edges: Vec<((usize, usize), (usize, usize))>,
+}
+
+impl Genome {
+    pub fn new(config: &SimConfig, rng: &mut impl Rng) -> Self {
+        match config.brain.kind {
+            BrainKind::Dense => {
+                let activations = vec![
+                    config.brain.hidden_activation,
+                    config.brain.output_activation,
+                ];
+                let init = &config.brain.init;
+                if config.brain.recurrent {
+                    Genome::Dense(Net::new_recurrent(
+                        config.brain_layer_sizes(),
+                        activations,
+                        init,
+                        rng,
+                    ))
+                } else {
+                    Genome::Dense(Net::with_init(
+                        config.brain_layer_sizes(),
+                        activations,
+                        init,
+                        rng,
+                    ))
+                }
+            }
+            BrainKind::Neat => Genome::Neat(NeatNet::new(
+                config.sensors.num_inputs(),
+                NUM_OUPUT_NODES,
+                config.brain.hidden_activation,
+                config.brain.output_activation,
+                &config.brain.init,
+                rng,
+            )),
+        }
+    }
+
+    pub fn predict(&self, inputs: &[f32]) -> Vec<Vec<f32>> {
+        match self {
+            Genome::Dense(net) => net.predict(inputs),
+            Genome::Neat(net) => net.predict(inputs),
+        }
+    }
+
+    pub fn try_predict(&self, inputs: &[f32]) -> Result<Vec<Vec<f32>>, NetError> {
+        match self {
+            Genome::Dense(net) => net.try_predict(inputs),
+            Genome::Neat(net) => net.try_predict(inputs),
+        }
+    }
+
+    /// Prediction into reused buffers that carries `memory` over from the
+    /// previous tick, NEAT genomes are feedforward and leave it untouched
+    pub fn predict_into(&self, inputs: &[f32], memory: &mut Memory, out: &mut Activations) {
+        self.try_predict_into(inputs, memory, out)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_predict_into(
+        &self,
+        inputs: &[f32],
+        memory: &mut Memory,
+        out: &mut Activations,
+    ) -> Result<(), NetError> {
+        match self {
+            Genome::Dense(net) => net.try_predict_into(inputs, memory, out),
+            Genome::Neat(net) => net.try_predict_into(inputs, out),
+        }
+    }
+
+    pub fn initial_memory(&self) -> Memory {
+        match self {
+            Genome::Dense(net) => net.initial_memory(),
+            Genome::Neat(_) => Vec::new(),
+        }
+    }
+
+    pub fn mutate_with(&mut self, params: &MutationParams, rng: &mut impl Rng) {
+        match self {
+            Genome::Dense(net) => net.mutate_with(params, rng),
+            Genome::Neat(net) => net.mutate_with(params, rng),
+        }
+    }
+
+    /// Topology mutations, dense nets have a fixed topology
+    pub fn mutate_structure(
+        &mut self,
+        params: &NeatParams,
+        innovations: &mut Innovations,
+        rng: &mut impl Rng,
+    ) {
+        if let Genome::Neat(net) = self {
+            net.mutate_structure(params, innovations, rng);
+        }
+    }
+
+    /// `self` is expected to be the fitter parent, NEAT genomes
+    /// line up their genes by innovation and ignore `kind`
+    pub fn crossover(&self, other: &Genome, kind: CrossoverKind, rng: &mut impl Rng) -> Genome {
+        match (self, other) {
+            (Genome::Dense(net), Genome::Dense(other)) => {
+                Genome::Dense(net.crossover(other, kind, rng))
+            }
+            (Genome::Neat(net), Genome::Neat(other)) => Genome::Neat(net.crossover(other, rng)),
+            _ => panic!("Crossover parents are different kinds of brain"),
+        }
+    }
+
+    pub fn output_activation(&self) -> Activation {
+        match self {
+            Genome::Dense(net) => net.output_activation(),
+            Genome::Neat(net) => net.output_activation(),
+        }
+    }
+
+    /// Brains of different kinds are infinitely far apart
+    pub fn distance(&self, other: &Genome) -> f32 {
+        match (self, other) {
+            (Genome::Dense(net), Genome::Dense(other)) => net.distance(other),
+            (Genome::Neat(net), Genome::Neat(other)) => net.distance(other),
+            _ => f32::INFINITY,
+        }
+    }
+
+    /// `activations` are the outputs of `predict`
+    pub fn graph(&self, activations: &[Vec<f32>]) -> BrainGraph {
+        match self {
+            Genome::Dense(net) => net.graph(activations),
+            Genome::Neat(net) => net.graph(activations),
+        }
+    }
+
+    /// Whether the brain can drive a car with the sensors and kind in `config`
+    pub fn check_config(&self, config: &SimConfig) -> Result<(), String> {
+        match (self, config.brain.kind) {
+            (Genome::Dense(net), BrainKind::Dense) => {
+                net.check_layer_sizes(&config.brain_layer_sizes())
+                    .map_err(|e| e.to_string())?;
+                if net.is_recurrent() != config.brain.recurrent {
+                    return Err(format!(
+                        "Brain recurrence doesn't match the config, recurrent is {}",
+                        config.brain.recurrent
+                    ));
+                }
+            }
+            (Genome::Neat(net), BrainKind::Neat) => {
+                let expected = (config.sensors.num_inputs(), NUM_OUPUT_NODES);
+                let found = (net.num_inputs(), net.num_outputs());
+                if found != expected {
+                    return Err(format!(
+                        "Brain inputs and outputs {:?} don't match the config {:?}",
+                        found, expected
+                    ));
+                }
+            }
+            (_, kind) => return Err(format!("Brain isn't a {:?} brain", kind)),
+        }
+        if config.car.controls == ControlScheme::Continuous
+            && self.output_activation().bounds().is_none()
+        {
+            return Err(format!(
+                "Continuous controls need bounded outputs, the brain outputs {:?}",
+                self.output_activation()
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BrainFileError> {
+        match self {
+            Genome::Dense(net) => net.save(path),
+            Genome::Neat(net) => net.save(path),
+        }
+    }
+
+    /// Loads either kind of brain file, a file that parses as neither
+    /// is reported as a malformed dense brain
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BrainFileError> {
+        let path = path.as_ref();
+        match Net::load(path) {
+            Ok(net) => Ok(Genome::Dense(net)),
+            Err(BrainFileError::Malformed(e)) => {
+                NeatNet::load(path)
+                    .map(Genome::Neat)
+                    .map_err(|neat_error| match neat_error {
+                        BrainFileError::Malformed(_) => BrainFileError::Malformed(e),
+                        neat_error => neat_error,
+                    })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
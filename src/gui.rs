@@ -3,12 +3,16 @@ use bevy_inspector_egui::{
     bevy_egui::EguiContexts,
     egui::{
         self,
+        ecolor::Hsva,
         epaint::CircleShape,
-        plot::{Line, Plot, PlotPoints},
+        plot::{Bar, BarChart, Line, Plot, PlotPoints},
         pos2, Color32, Shape, Stroke,
     },
 };
 
+use crate::car::{Brain, CarControls, Elite};
+use crate::simulation::SimulationClock;
+use crate::species::SpeciesTracker;
 use crate::*;
 
 pub struct GuiPlugin;
@@ -25,14 +29,12 @@ struct CarProgressIcon;
 impl Plugin for GuiPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_startup_system(setup)
-            .insert_resource(BrainToDisplay::default())
-            .insert_resource(Settings::default())
-            .insert_resource(SimStats::default())
             .add_system(stats_dialog_system)
             .add_system(generation_count_stats_system)
             .add_system(max_score_stats_system)
             .add_system(num_cars_stats_system)
             .add_system(car_progress_system)
+            .add_system(elite_marker_system)
             .add_system(nn_viz_system);
     }
 }
@@ -191,7 +193,10 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
 fn stats_dialog_system(
     mut contexts: EguiContexts,
     sim_stats: Res<SimStats>,
+    adaptive_mutation: Res<AdaptiveMutation>,
+    species_tracker: Res<SpeciesTracker>,
     mut settings: ResMut<Settings>,
+    mut clock: ResMut<SimulationClock>,
 ) {
     let ctx = contexts.ctx_mut();
 
@@ -210,8 +215,25 @@ fn stats_dialog_system(
                     Plot::new("fitness_curve")
                         .view_aspect(2.0)
                         .show(ui, |plot_ui| plot_ui.line(line));
+                    ui.label(format!("Mutation sigma: {:.3}", adaptive_mutation.sigma));
                 });
 
+            if !species_tracker.history.is_empty() {
+                egui::CollapsingHeader::new("Species")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        let chart = BarChart::new(species_size_bars(&species_tracker.history));
+                        Plot::new("species_sizes")
+                            .view_aspect(2.0)
+                            .show(ui, |plot_ui| plot_ui.bar_chart(chart));
+                        ui.label(format!(
+                            "Species: {}, threshold: {:.3}",
+                            species_tracker.species.len(),
+                            species_tracker.threshold
+                        ));
+                    });
+            }
+
             egui::CollapsingHeader::new("Settings")
                 .default_open(true)
                 .show(ui, |ui| {
@@ -232,114 +254,135 @@ fn stats_dialog_system(
                     if ui.button("Restart Simulation").clicked() {
                         settings.restart_sim = true;
                     };
+
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        let pause_label = if clock.is_paused { "Resume" } else { "Pause" };
+                        if ui.button(pause_label).clicked() {
+                            clock.is_paused = !clock.is_paused;
+                        };
+                        if ui
+                            .add_enabled(clock.is_paused, egui::Button::new("Step tick"))
+                            .clicked()
+                        {
+                            clock.pending_steps += 1;
+                        };
+                        if ui.button("Step generation").clicked() {
+                            clock.is_paused = false;
+                            clock.pause_on_next_generation = true;
+                        };
+                    });
+                    ui.add_enabled(
+                        !clock.is_max_speed,
+                        egui::Slider::new(&mut clock.speed, 0.25..=16.0)
+                            .logarithmic(true)
+                            .text("Speed"),
+                    );
+                    ui.checkbox(&mut clock.is_max_speed, "As fast as possible");
                 });
         });
 }
 
-fn nn_viz_system(mut contexts: EguiContexts, best_brain: Res<BrainToDisplay>) {
-    if best_brain.0.is_empty() {
-        return;
-    }
+fn nn_viz_system(
+    mut contexts: EguiContexts,
+    config: Res<SimConfig>,
+    best_brain: Res<BrainToDisplay>,
+    brain_query: Query<&Brain>,
+) {
+    let brain = match best_brain.0.and_then(|e| brain_query.get(e).ok()) {
+        Some(brain) if !brain.activations.is_empty() => brain,
+        _ => return,
+    };
+    let graph = brain.nn.graph(&brain.activations.to_columns());
+    let controls = CarControls::from_nn_outputs(
+        brain.activations.output(),
+        brain.nn.output_activation(),
+        config.car.controls,
+    );
 
     let ctx = contexts.ctx_mut();
     let mut shapes = Vec::new();
     let tot_height = 700.0;
+    let last = graph.columns.len() - 1;
 
-    // NN viz points
-    let points1 = get_nn_viz_points(NUM_RAY_CASTS as usize, tot_height - 100.0);
-    let points2 = get_nn_viz_points(NUM_HIDDEN_NODES as usize, tot_height);
-    let points3 = get_nn_viz_points(NUM_OUPUT_NODES as usize, tot_height - 300.0);
-    // NN ouput
-    let values1 = best_brain.0[0].clone();
-    let values2 = best_brain.0[1].clone();
-    let values3 = best_brain.0[2].clone();
-    // x's
-    let x1 = 75.0;
-    let x2 = 225.0;
-    let x3 = 375.0;
-    // Padding
-    let padding1 = 100.0;
-    let padding2 = 50.0;
-    let padding3 = 200.0;
     // colors
-    let colors1: Vec<Color32> = values1
+    let colors: Vec<Vec<Color32>> = graph
+        .columns
         .iter()
-        .rev()
-        .map(|v| {
-            if *v != 1.0 {
-                Color32::GREEN
-            } else {
-                Color32::RED
-            }
+        .enumerate()
+        .map(|(column, values)| match column {
+            0 => values
+                .iter()
+                .map(|v| {
+                    if *v != 1.0 {
+                        Color32::GREEN
+                    } else {
+                        Color32::RED
+                    }
+                })
+                .collect(),
+            c if c == last => get_nn_output_colors(&controls),
+            _ => values
+                .iter()
+                .map(|v| {
+                    if *v > 0.5 {
+                        Color32::GREEN
+                    } else {
+                        Color32::RED
+                    }
+                })
+                .collect(),
         })
         .collect();
-    let colors2: Vec<Color32> = values2
+
+    // NN viz points, columns are spread between the x's of the inputs and outputs
+    let positions: Vec<Vec<egui::Pos2>> = graph
+        .columns
         .iter()
-        .rev()
-        .map(|v| {
-            if *v > 0.5 {
-                Color32::GREEN
-            } else {
-                Color32::RED
+        .enumerate()
+        .map(|(column, values)| {
+            let x = 75.0 + 300.0 * column as f32 / last as f32;
+            let height: f32 = match column {
+                0 => tot_height - 100.0,
+                c if c == last => tot_height - 300.0,
+                _ => tot_height,
+            };
+            let height =
+                height.min(values.len().saturating_sub(1) as f32 * NN_VIZ_MAX_NODE_SPACING);
+            let padding = 50.0 + (tot_height - height) / 2.0;
+
+            let mut points: Vec<egui::Pos2> = get_nn_viz_points(values.len(), height)
+                .iter()
+                .map(|y| pos2(x, y + padding))
+                .collect();
+            // Only the outputs are drawn top to bottom
+            if column != last {
+                points.reverse();
             }
+            points
         })
         .collect();
-    let mut colors3 = vec![Color32::RED, Color32::RED, Color32::RED];
-    if values3[1] >= 0.5 {
-        colors3[0] = Color32::GREEN;
-    } else {
-        colors3[1] = Color32::GREEN;
-    }
 
-    // if values3[0] >= NN_W_ACTIVATION_THRESHOLD {
-    //     colors3[0] = Color32::GREEN;
-    // }
-    // if values3[2] >= NN_S_ACTIVATION_THRESHOLD {
-    //     colors3[2] = Color32::GREEN;
-    // }
-
-    // layer 1 -> 2 lines
-    for (p1, c1) in points1.iter().zip(colors1.iter()) {
-        for (p2, c2) in points2.iter().zip(colors2.iter()) {
-            let mut color = Color32::RED;
-            if are_colors_equad(*c1, *c2) {
-                color = Color32::GREEN;
-            }
-            shapes.push(egui::Shape::line(
-                vec![pos2(x1, *p1 + padding1), pos2(x2, *p2 + padding2)],
-                Stroke { width: 0.3, color },
-            ));
+    // connections
+    for &((c1, r1), (c2, r2)) in graph.edges.iter() {
+        let mut color = Color32::RED;
+        if are_colors_equad(colors[c1][r1], colors[c2][r2]) {
+            color = Color32::GREEN;
         }
+        shapes.push(egui::Shape::line(
+            vec![positions[c1][r1], positions[c2][r2]],
+            Stroke { width: 0.3, color },
+        ));
     }
 
-    // layer 2 -> 3 lines
-    for (p2, c2) in points2.iter().zip(colors2.iter()) {
-        for (p3, c3) in points3.iter().zip(colors3.iter()) {
-            let mut color = Color32::RED;
-            if are_colors_equad(*c2, *c3) {
-                color = Color32::GREEN;
-            }
-            shapes.push(egui::Shape::line(
-                vec![pos2(x2, *p2 + padding2), pos2(x3, *p3 + padding3)],
-                Stroke { width: 0.3, color },
-            ));
+    // nodes
+    for (column_positions, column_colors) in positions.iter().zip(colors.iter()) {
+        for (p, c) in column_positions.iter().zip(column_colors.iter()) {
+            shapes.push(get_nn_node_shape(p.x, p.y, *c));
         }
     }
 
-    // layer 1
-    for (p, c) in points1.iter().zip(colors1.iter()) {
-        shapes.push(get_nn_node_shape(x1, *p + padding1, *c));
-    }
-    // layer 2
-    for (p, c) in points2.iter().zip(colors2.iter()) {
-        shapes.push(get_nn_node_shape(x2, *p + padding2, *c));
-    }
-    // layer 3
-    for (p, c) in points3.iter().zip(colors3.iter()) {
-        shapes.push(get_nn_node_shape(x3, *p + padding3, *c));
-    }
-
-    shapes.append(&mut arrow_keys_viz_system(colors3));
+    shapes.append(&mut arrow_keys_viz_system(&controls));
     egui::SidePanel::left("left")
         .min_width(400.0)
         .show(ctx, |ui| {
@@ -349,6 +392,21 @@ fn nn_viz_system(mut contexts: EguiContexts, best_brain: Res<BrainToDisplay>) {
         });
 }
 
+/// Throttle, steering (green turns left) and brake
+fn get_nn_output_colors(controls: &CarControls) -> Vec<Color32> {
+    vec![
+        get_control_color(controls.throttle),
+        get_control_color((controls.steer + 1.0) / 2.0),
+        get_control_color(controls.brake),
+    ]
+}
+
+/// Red when released, green when fully pressed
+fn get_control_color(amount: f32) -> Color32 {
+    let amount = amount.clamp(0.0, 1.0);
+    Color32::from_rgb((255.0 * (1.0 - amount)) as u8, (255.0 * amount) as u8, 0)
+}
+
 fn generation_count_stats_system(
     stats: Res<SimStats>,
     mut q_generation_text: Query<&mut Text, With<GenerationCountLabel>>,
@@ -358,11 +416,13 @@ fn generation_count_stats_system(
 }
 
 fn num_cars_stats_system(
+    config: Res<SimConfig>,
     stats: Res<SimStats>,
     mut q_num_cars_text: Query<&mut Text, With<CarsAliveLabel>>,
 ) {
     let mut num_cars_text = q_num_cars_text.single_mut();
-    num_cars_text.sections[0].value = format!("Cars: \n{}/{}", stats.num_cars_alive, NUM_AI_CARS);
+    num_cars_text.sections[0].value =
+        format!("Cars: \n{}/{}", stats.num_cars_alive, config.num_ai_cars);
 }
 
 fn max_score_stats_system(
@@ -381,7 +441,34 @@ fn car_progress_system(
     style.position.bottom = Val::Percent(stats.max_current_score - 3.0);
 }
 
-fn arrow_keys_viz_system(colors: Vec<Color32>) -> Vec<Shape> {
+/// Species sizes of each generation stacked on top of each other
+fn species_size_bars(history: &[Vec<(u32, usize)>]) -> Vec<Bar> {
+    let mut bars = Vec::new();
+    for (generation, sizes) in history.iter().enumerate() {
+        let mut offset = 0.0;
+        for &(id, size) in sizes.iter() {
+            // Golden ratio hues keep neighbouring ids apart
+            let hue = (id as f32 * 0.618_034).fract();
+            bars.push(
+                Bar::new(generation as f64, size as f64)
+                    .base_offset(offset)
+                    .width(1.0)
+                    .fill(Hsva::new(hue, 0.7, 0.9, 1.0)),
+            );
+            offset += size as f64;
+        }
+    }
+
+    bars
+}
+
+fn elite_marker_system(mut q_elites: Query<&mut Sprite, Added<Elite>>) {
+    for mut sprite in q_elites.iter_mut() {
+        sprite.color = Color::GOLD;
+    }
+}
+
+fn arrow_keys_viz_system(controls: &CarControls) -> Vec<Shape> {
     // wasd buttons
     let x = 75.0;
     let y = 800.0;
@@ -394,7 +481,7 @@ fn arrow_keys_viz_system(colors: Vec<Color32>) -> Vec<Shape> {
             max: egui::pos2(150.0 + x, 150.0 + y),
         },
         10.0,
-        Color32::GREEN,
+        get_control_color(controls.throttle),
     ));
     // a
     shapes.push(egui::Shape::rect_filled(
@@ -403,7 +490,7 @@ fn arrow_keys_viz_system(colors: Vec<Color32>) -> Vec<Shape> {
             max: egui::pos2(90.0 + x, 210.0 + y),
         },
         10.0,
-        colors[0],
+        get_control_color(controls.steer),
     ));
     // s
     shapes.push(egui::Shape::rect_filled(
@@ -412,7 +499,7 @@ fn arrow_keys_viz_system(colors: Vec<Color32>) -> Vec<Shape> {
             max: egui::pos2(150.0 + x, 210.0 + y),
         },
         10.0,
-        Color32::RED,
+        get_control_color(controls.brake),
     ));
     // d
     shapes.push(egui::Shape::rect_filled(
@@ -421,7 +508,7 @@ fn arrow_keys_viz_system(colors: Vec<Color32>) -> Vec<Shape> {
             max: egui::pos2(210.0 + x, 210.0 + y),
         },
         10.0,
-        colors[1],
+        get_control_color(-controls.steer),
     ));
 
     shapes
@@ -440,7 +527,11 @@ fn get_nn_node_shape(x: f32, y: f32, color: Color32) -> egui::Shape {
 }
 
 fn get_nn_viz_points(n: usize, tot_size: f32) -> Vec<f32> {
-    let point_spacing = tot_size / (n - 1) as f32;
+    let point_spacing = if n > 1 {
+        tot_size / (n - 1) as f32
+    } else {
+        0.0
+    };
     let mut points = Vec::new();
 
     for i in 0..n {
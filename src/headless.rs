@@ -0,0 +1,49 @@
+use bevy::{app::AppExit, prelude::*};
+
+use crate::simulation::{SimSet, SimulationClock, SimulationTick};
+use crate::*;
+
+/// Runs the training without a window, printing per generation stats to stdout
+pub struct HeadlessPlugin;
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(setup)
+            .add_system(
+                generation_stats_system
+                    .in_set(SimSet::Report)
+                    .in_schedule(SimulationTick),
+            )
+            .add_system(generation_limit_system);
+    }
+}
+
+fn setup(mut clock: ResMut<SimulationClock>) {
+    // Nothing is rendered, run as fast as the machine allows
+    clock.is_max_speed = true;
+}
+
+fn generation_stats_system(sim_stats: Res<SimStats>, mut last_generation: Local<Option<u32>>) {
+    let generation = sim_stats.generation_count;
+    if *last_generation == Some(generation) {
+        return;
+    }
+    *last_generation = Some(generation);
+
+    if let Some(max_fitness) = sim_stats.fitness.last() {
+        println!("Gen: {}, Max fitness: {:.2}", generation, max_fitness);
+    }
+}
+
+fn generation_limit_system(
+    config: Res<SimConfig>,
+    sim_stats: Res<SimStats>,
+    mut start_generation: Local<Option<u32>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    // Count from the first generation seen, runs can resume from a checkpoint
+    let start = *start_generation.get_or_insert(sim_stats.generation_count);
+    if sim_stats.generation_count.saturating_sub(start) >= config.headless.max_generations {
+        exit.send(AppExit);
+    }
+}
@@ -1,10 +1,25 @@
 pub mod car;
+pub mod checkpoint;
 pub mod configs;
 pub mod enemy;
+pub mod genome;
 pub mod gui;
+pub mod headless;
+pub mod neat;
 pub mod nn;
 pub mod population;
 pub mod resources;
+pub mod road;
+pub mod selection;
+pub mod simulation;
+pub mod species;
+
+use bevy::prelude::{AssetServer, Handle, Image};
 
 pub use configs::*;
 pub use resources::*;
+
+/// Headless apps have no asset server, their sprites get an empty texture
+pub fn load_texture(asset_server: Option<&AssetServer>, path: &str) -> Handle<Image> {
+    asset_server.map_or_else(Handle::default, |asset_server| asset_server.load(path))
+}
@@ -1,28 +1,144 @@
+use std::path::PathBuf;
+
 use bevy::{
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    log::LogPlugin,
     math::vec3,
     prelude::*,
     window::{PresentMode, WindowMode},
 };
 use bevy_inspector_egui::{bevy_egui::EguiPlugin, DefaultInspectorConfigPlugin};
 use bevy_pancam::{PanCam, PanCamPlugin};
+use bevy_prototype_debug_lines::DebugLinesPlugin;
 use bevy_rapier2d::{
-    prelude::{Collider, NoUserData, RapierConfiguration, RapierPhysicsPlugin, RigidBody},
+    prelude::{NoUserData, RapierPhysicsPlugin},
     render::RapierDebugRenderPlugin,
 };
+use clap::Parser;
 
 use steering::{
     car::{Car, CarPlugin},
+    checkpoint::{load_resume_checkpoint, CheckpointPlugin, ResumeCheckpoint},
     gui::GuiPlugin,
+    headless::HeadlessPlugin,
     population::PopulationPlugin,
+    road::RoadPlugin,
+    simulation::SimulationPlugin,
 };
-use steering::{
-    enemy::{spawn_bound_trucks, EnemyPlugin},
-    *,
-};
+use steering::{enemy::EnemyPlugin, *};
+
+/// AI learns to drive, trained with a genetic algorithm
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Simulation config file [default: config.ron]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Train without a window, printing per generation stats
+    #[arg(long)]
+    headless: bool,
+
+    /// Seed for the simulation RNG
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of generations to train for in headless mode
+    #[arg(long)]
+    generations: Option<u32>,
+
+    /// Spawn the population from a saved brain
+    #[arg(long, conflicts_with = "checkpoint")]
+    brain: Option<PathBuf>,
+
+    /// Resume from a checkpoint file
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Resume from the latest checkpoint
+    #[arg(long, conflicts_with = "brain")]
+    resume: bool,
+
+    /// Directory for the stats file and checkpoints
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+}
 
 fn main() {
+    let cli = Cli::parse();
+    let config_path = cli.config.clone().unwrap_or(PathBuf::from(CONFIG_PATH));
+    let config = match &cli.config {
+        Some(path) => SimConfig::load(path),
+        None => SimConfig::load_or_default(&config_path),
+    }
+    .map(|config| cli.apply(config))
+    .and_then(|config| config.validate().map(|_| config));
+
+    let mut config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", config_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // Always run seeded, a run can be reproduced from the printed seed
+    let seed = *config.seed.get_or_insert_with(rand::random);
+    println!("Seed: {}", seed);
+    let mut rng = SimRng::from_seed(seed);
+
+    // A broken latest checkpoint only means starting a new run
+    let is_explicit = config.brain.load_from.is_some() || config.checkpoint.resume_from.is_some();
+    let resume = match load_resume_checkpoint(&config, &mut rng.mutation) {
+        Ok(checkpoint) => ResumeCheckpoint(checkpoint),
+        Err(e) if is_explicit => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}, starting a new run", e);
+            ResumeCheckpoint::default()
+        }
+    };
+
+    if cli.headless {
+        run_headless(config, rng, resume);
+    } else {
+        run_windowed(config, rng, resume);
+    }
+}
+
+impl Cli {
+    fn apply(&self, mut config: SimConfig) -> SimConfig {
+        if let Some(seed) = self.seed {
+            config.seed = Some(seed);
+        }
+        if let Some(generations) = self.generations {
+            config.headless.max_generations = generations;
+        }
+        if let Some(path) = &self.brain {
+            config.brain.load_from = Some(path.clone());
+        }
+        if let Some(path) = &self.checkpoint {
+            config.checkpoint.resume_from = Some(path.clone());
+        }
+        if self.resume {
+            config.checkpoint.resume = true;
+        }
+        if let Some(dir) = &self.out_dir {
+            config.checkpoint.dir = dir.join("checkpoints");
+            config.stats_file = Some(dir.join("stats.csv"));
+        }
+
+        config
+    }
+}
+
+fn run_windowed(config: SimConfig, rng: SimRng, resume: ResumeCheckpoint) {
     App::new()
+        .insert_resource(rng)
+        .insert_resource(config)
+        .insert_resource(resume)
         .add_plugins(
             DefaultPlugins
                 .set(ImagePlugin::default_nearest())
@@ -42,12 +158,19 @@ fn main() {
         // .add_plugin(WorldInspectorPlugin::new().run_if(input_toggle_active(false, KeyCode::Tab))) // remove eguiplugin
         .add_plugin(DefaultInspectorConfigPlugin) // Requires egui plugin
         .add_plugin(EguiPlugin)
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
+        .add_plugin(DebugLinesPlugin::default())
+        .add_plugin(
+            RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0)
+                .with_default_system_setup(false),
+        )
+        .add_plugin(SimulationPlugin)
         // .add_plugin(LogDiagnosticsPlugin::default())
         // .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(RoadPlugin)
         .add_plugin(CarPlugin)
         .add_plugin(EnemyPlugin)
         .add_plugin(PopulationPlugin)
+        .add_plugin(CheckpointPlugin)
         .add_plugin(GuiPlugin)
         // .add_plugin(RapierDebugRenderPlugin::default())
         .insert_resource(ClearColor(Color::rgb_u8(36, 36, 36)))
@@ -60,22 +183,36 @@ fn main() {
         .run();
 }
 
-fn setup(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut rapier_config: ResMut<RapierConfiguration>,
-) {
-    rapier_config.gravity = Vec2::ZERO;
+fn run_headless(config: SimConfig, rng: SimRng, resume: ResumeCheckpoint) {
+    App::new()
+        .insert_resource(rng)
+        .insert_resource(config)
+        .insert_resource(resume)
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LogPlugin::default())
+        .add_plugin(TransformPlugin)
+        .add_plugin(HierarchyPlugin)
+        .add_plugin(
+            RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0)
+                .with_default_system_setup(false),
+        )
+        .add_plugin(SimulationPlugin)
+        .add_plugin(RoadPlugin)
+        .add_plugin(CarPlugin)
+        .add_plugin(EnemyPlugin)
+        .add_plugin(PopulationPlugin)
+        .add_plugin(CheckpointPlugin)
+        .add_plugin(HeadlessPlugin)
+        .run();
+}
 
+fn setup(mut commands: Commands) {
     commands
         .spawn(Camera2dBundle {
             transform: Transform::from_xyz(WINDOW_WIDTH / 2.0, WINDOW_HEIGHT / 2.0, 0.0),
             ..default()
         })
         .insert(PanCam::default());
-
-    spawn_roads(&mut commands, &asset_server);
-    spawn_bound_trucks(&mut commands, &asset_server);
 }
 
 fn camera_follow_system(
@@ -92,68 +229,6 @@ fn camera_follow_system(
     }
 }
 
-fn spawn_roads(commands: &mut Commands, asset_server: &AssetServer) {
-    // Road
-    let rx = WINDOW_WIDTH / 2.0 - 30.0;
-    let mut ry = ROAD_SPRITE_H / 2.0 * SPRITE_SCALE_FACTOR;
-    for _ in 0..NUM_ROAD_TILES {
-        commands.spawn(SpriteBundle {
-            transform: Transform::from_xyz(rx, ry, -10.0)
-                .with_scale(Vec3::splat(SPRITE_SCALE_FACTOR)),
-            texture: asset_server.load("road.png"),
-            ..default()
-        });
-        ry += ROAD_SPRITE_H * SPRITE_SCALE_FACTOR;
-    }
-    let road_end_y = ry - ROAD_SPRITE_H * SPRITE_SCALE_FACTOR + 800.0;
-
-    // end checker board
-    commands.spawn(SpriteBundle {
-        transform: Transform::from_xyz(rx, road_end_y - 50.0, -5.0)
-            .with_scale(Vec3::splat(SPRITE_SCALE_FACTOR)),
-        texture: asset_server.load("end-point.png"),
-        ..default()
-    });
-
-    // Road colliders
-    // left
-    let ry = 5.0 * ROAD_SPRITE_H * SPRITE_SCALE_FACTOR;
-    let rx_min = ROAD_SPRITE_W / 2.0 * SPRITE_SCALE_FACTOR + 238.0;
-    commands.spawn((
-        SpriteBundle {
-            transform: Transform::from_xyz(rx_min, ry, 0.0).with_scale(vec3(0.5, 0.5, 1.0)),
-            ..default()
-        },
-        RigidBody::Fixed,
-        Collider::cuboid(
-            5.0,
-            ROAD_SPRITE_H * SPRITE_SCALE_FACTOR * NUM_ROAD_TILES as f32 * 5.0,
-        ),
-    ));
-    // right
-    let rx_max = ROAD_SPRITE_W * SPRITE_SCALE_FACTOR + 248.0;
-    commands.spawn((
-        SpriteBundle {
-            transform: Transform::from_xyz(rx_max, ry, 0.0).with_scale(vec3(0.5, 0.5, 1.0)),
-            ..default()
-        },
-        RigidBody::Fixed,
-        Collider::cuboid(
-            5.0,
-            ROAD_SPRITE_H * SPRITE_SCALE_FACTOR * NUM_ROAD_TILES as f32 * 5.0,
-        ),
-    ));
-    // top
-    commands.spawn((
-        SpriteBundle {
-            transform: Transform::from_xyz(600.0, road_end_y, 0.0).with_scale(vec3(0.5, 0.5, 1.0)),
-            ..default()
-        },
-        RigidBody::Fixed,
-        Collider::cuboid(500.0 * SPRITE_SCALE_FACTOR, 10.0),
-    ));
-}
-
 fn settings_system(
     mut commands: Commands,
     mut settings: ResMut<Settings>,
@@ -0,0 +1,771 @@
+use std::collections::{HashMap, HashSet};
+use std::{fs, path::Path};
+
+use bevy::prelude::Resource;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::genome::BrainGraph;
+use crate::nn::{
+    validate_layer_sizes, Activation, Activations, BrainFileError, InitParams, MutationParams,
+    NetError,
+};
+
+/// Version of the on-disk NEAT genome format, bump it whenever `SavedNeatNet` changes.
+/// Version 1 genomes predate activations and load as all sigmoid
+pub const NEAT_FORMAT_VERSION: u32 = 2;
+
+/// Random node pairs tried when looking for a new connection
+const ADD_CONNECTION_TRIES: usize = 20;
+
+/// Net with an evolving topology, built from NEAT node and connection genes.
+/// Node ids `0..n_inputs` are the inputs and the next `n_outputs` ids the outputs,
+/// hidden nodes get their ids from `Innovations`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "SavedNeatNet", try_from = "SavedNeatNet")]
+pub struct NeatNet {
+    n_inputs: usize,
+    n_outputs: usize,
+    hidden_activation: Activation,
+    output_activation: Activation,
+    /// Outputs first, then the hidden nodes. Inputs have no gene
+    nodes: Vec<NodeGene>,
+    /// Sorted by innovation number
+    connections: Vec<ConnectionGene>,
+    /// Indices into `nodes` in evaluation order
+    order: Vec<usize>,
+    /// Enabled connections into each node, as (value index, connection index)
+    incoming: Vec<Vec<(usize, usize)>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeGene {
+    pub id: usize,
+    pub bias: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConnectionGene {
+    pub innovation: u64,
+    pub from: usize,
+    pub to: usize,
+    pub weight: f32,
+    pub enabled: bool,
+}
+
+/// On-disk representation of a `NeatNet`
+#[derive(Serialize, Deserialize)]
+struct SavedNeatNet {
+    version: u32,
+    n_inputs: usize,
+    n_outputs: usize,
+    #[serde(default)]
+    hidden_activation: Activation,
+    #[serde(default)]
+    output_activation: Activation,
+    nodes: Vec<NodeGene>,
+    connections: Vec<ConnectionGene>,
+}
+
+/// Chances of the structural mutations, per child
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NeatParams {
+    pub add_node_rate: f32,
+    pub add_connection_rate: f32,
+}
+
+/// Innovation numbers shared by the whole population, the same structural
+/// change gets the same number so that crossover can line up the genes
+#[derive(Resource)]
+pub struct Innovations {
+    connections: HashMap<(usize, usize), u64>,
+    /// Hidden node created by splitting a connection
+    splits: HashMap<u64, usize>,
+    next_innovation: u64,
+    next_node: usize,
+}
+
+impl NeatNet {
+    /// Every input connected to every output, no hidden nodes
+    pub fn new(
+        n_inputs: usize,
+        n_outputs: usize,
+        hidden_activation: Activation,
+        output_activation: Activation,
+        init: &InitParams,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::try_new(
+            n_inputs,
+            n_outputs,
+            hidden_activation,
+            output_activation,
+            init,
+            rng,
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(
+        n_inputs: usize,
+        n_outputs: usize,
+        hidden_activation: Activation,
+        output_activation: Activation,
+        init: &InitParams,
+        rng: &mut impl Rng,
+    ) -> Result<Self, NetError> {
+        validate_layer_sizes(&[n_inputs, n_outputs])?;
+
+        let nodes = (n_inputs..n_inputs + n_outputs)
+            .map(|id| NodeGene {
+                id,
+                bias: init.bias(n_inputs, n_outputs, rng),
+            })
+            .collect();
+        let mut connections = Vec::new();
+        for from in 0..n_inputs {
+            for to in n_inputs..n_inputs + n_outputs {
+                connections.push(ConnectionGene {
+                    innovation: initial_innovation(n_inputs, n_outputs, from, to),
+                    from,
+                    to,
+                    weight: init.weights.sample(n_inputs, n_outputs, rng),
+                    enabled: true,
+                });
+            }
+        }
+
+        let mut net = Self {
+            n_inputs,
+            n_outputs,
+            hidden_activation,
+            output_activation,
+            nodes,
+            connections,
+            order: Vec::new(),
+            incoming: Vec::new(),
+        };
+        net.rebuild().expect("Initial genome has a cycle");
+        Ok(net)
+    }
+
+    /// Same contract as `Net::predict`, the inputs first and the outputs last,
+    /// in between are the hidden node values. Panics on a bad input size
+    pub fn predict(&self, inputs: &[f32]) -> Vec<Vec<f32>> {
+        self.try_predict(inputs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_predict(&self, inputs: &[f32]) -> Result<Vec<Vec<f32>>, NetError> {
+        let mut activations = Activations::default();
+        self.try_predict_into(inputs, &mut activations)?;
+        Ok(activations.to_columns())
+    }
+
+    /// Prediction into a reused buffer, laid out as `predict` returns it.
+    /// Panics on a bad input size
+    pub fn predict_into(&self, inputs: &[f32], out: &mut Activations) {
+        self.try_predict_into(inputs, out)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_predict_into(&self, inputs: &[f32], out: &mut Activations) -> Result<(), NetError> {
+        if inputs.len() != self.n_inputs {
+            return Err(NetError::InputSize {
+                expected: self.n_inputs,
+                found: inputs.len(),
+            });
+        }
+
+        let n_hidden = self.nodes.len() - self.n_outputs;
+        out.prepare([self.n_inputs, n_hidden, self.n_outputs].into_iter());
+        // Value indices are the inputs followed by the nodes, outputs first,
+        // `out` has the hidden nodes before the outputs
+        let slot = |value_idx: usize| match value_idx.checked_sub(self.n_inputs) {
+            None => value_idx,
+            Some(idx) if idx < self.n_outputs => self.n_inputs + n_hidden + idx,
+            Some(_) => value_idx - self.n_outputs,
+        };
+
+        let values = out.values_mut();
+        values[..self.n_inputs].copy_from_slice(inputs);
+        for &idx in self.order.iter() {
+            let mut total = self.nodes[idx].bias;
+            for &(value_idx, conn_idx) in self.incoming[idx].iter() {
+                total += values[slot(value_idx)] * self.connections[conn_idx].weight;
+            }
+            let activation = if idx < self.n_outputs {
+                self.output_activation
+            } else {
+                self.hidden_activation
+            };
+            values[slot(self.n_inputs + idx)] = activation.apply(total);
+        }
+
+        Ok(())
+    }
+
+    pub fn mutate_with(&mut self, params: &MutationParams, rng: &mut impl Rng) {
+        for node in self.nodes.iter_mut() {
+            params.mutate_gene(&mut node.bias, rng);
+        }
+        for conn in self.connections.iter_mut() {
+            params.mutate_gene(&mut conn.weight, rng);
+        }
+    }
+
+    /// Adds nodes and connections, with the chances in `params`
+    pub fn mutate_structure(
+        &mut self,
+        params: &NeatParams,
+        innovations: &mut Innovations,
+        rng: &mut impl Rng,
+    ) {
+        if rng.gen::<f32>() < params.add_connection_rate {
+            self.add_connection(innovations, rng);
+        }
+        if rng.gen::<f32>() < params.add_node_rate {
+            self.add_node(innovations, rng);
+        }
+
+        self.rebuild().expect("Structural mutation made a cycle");
+    }
+
+    /// Matching genes come from either parent, the others from `self`,
+    /// which is expected to be the fitter parent
+    pub fn crossover(&self, other: &NeatNet, rng: &mut impl Rng) -> NeatNet {
+        let other_connections: HashMap<u64, &ConnectionGene> = other
+            .connections
+            .iter()
+            .map(|c| (c.innovation, c))
+            .collect();
+        let other_biases: HashMap<usize, f32> =
+            other.nodes.iter().map(|n| (n.id, n.bias)).collect();
+
+        let mut child = self.clone();
+        for conn in child.connections.iter_mut() {
+            if let Some(other_conn) = other_connections.get(&conn.innovation) {
+                if rng.gen_bool(0.5) {
+                    conn.weight = other_conn.weight;
+                    conn.enabled = other_conn.enabled;
+                }
+            }
+        }
+        for node in child.nodes.iter_mut() {
+            if let Some(bias) = other_biases.get(&node.id) {
+                if rng.gen_bool(0.5) {
+                    node.bias = *bias;
+                }
+            }
+        }
+
+        child.rebuild().expect("Crossover made a cycle");
+        child
+    }
+
+    /// Share of unmatched connection genes plus the mean
+    /// weight difference of the matching ones
+    pub fn distance(&self, other: &NeatNet) -> f32 {
+        let other_weights: HashMap<u64, f32> = other
+            .connections
+            .iter()
+            .map(|c| (c.innovation, c.weight))
+            .collect();
+
+        let mut num_matching = 0;
+        let mut weight_diff = 0.0;
+        for conn in self.connections.iter() {
+            if let Some(weight) = other_weights.get(&conn.innovation) {
+                num_matching += 1;
+                weight_diff += (conn.weight - weight).abs();
+            }
+        }
+
+        let num_unmatched = self.connections.len() + other.connections.len() - 2 * num_matching;
+        let num_genes = self.connections.len().max(other.connections.len()).max(1);
+        let mean_weight_diff = if num_matching > 0 {
+            weight_diff / num_matching as f32
+        } else {
+            0.0
+        };
+
+        num_unmatched as f32 / num_genes as f32 + mean_weight_diff
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.n_inputs
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.n_outputs
+    }
+
+    pub fn output_activation(&self) -> Activation {
+        self.output_activation
+    }
+
+    /// Nodes in columns by their distance from the inputs, outputs in the last column
+    pub fn graph(&self, activations: &[Vec<f32>]) -> BrainGraph {
+        let mut depths = vec![0; self.nodes.len()];
+        for &idx in self.order.iter() {
+            depths[idx] = self.incoming[idx]
+                .iter()
+                .map(
+                    |&(value_idx, _)| match value_idx.checked_sub(self.n_inputs) {
+                        Some(node_idx) => depths[node_idx] + 1,
+                        None => 1,
+                    },
+                )
+                .max()
+                .unwrap_or(1);
+        }
+        let output_depth = depths[self.n_outputs..]
+            .iter()
+            .map(|d| d + 1)
+            .max()
+            .unwrap_or(1);
+
+        // (column, row) of each value index
+        let mut columns = vec![Vec::new(); output_depth + 1];
+        let mut positions = Vec::new();
+        for (row, value) in activations[0].iter().enumerate() {
+            columns[0].push(*value);
+            positions.push((0, row));
+        }
+        for (idx, depth) in depths.iter().enumerate() {
+            let column = if idx < self.n_outputs {
+                output_depth
+            } else {
+                *depth
+            };
+            let value = if idx < self.n_outputs {
+                activations[2][idx]
+            } else {
+                activations[1]
+                    .get(idx - self.n_outputs)
+                    .copied()
+                    .unwrap_or(0.0)
+            };
+            positions.push((column, columns[column].len()));
+            columns[column].push(value);
+        }
+        let edges = self
+            .connections
+            .iter()
+            .filter(|c| c.enabled)
+            .map(|c| {
+                (
+                    positions[self.value_index(c.from)],
+                    positions[self.value_index(c.to)],
+                )
+            })
+            .collect();
+
+        BrainGraph { columns, edges }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BrainFileError> {
+        let contents = ron::ser::to_string_pretty(
+            &self.to_saved(),
+            ron::ser::PrettyConfig::default().depth_limit(2),
+        )
+        .map_err(|e| BrainFileError::Malformed(e.to_string()))?;
+
+        fs::write(path, contents).map_err(BrainFileError::Io)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BrainFileError> {
+        let contents = fs::read_to_string(path).map_err(BrainFileError::Io)?;
+        let saved: SavedNeatNet =
+            ron::from_str(&contents).map_err(|e| BrainFileError::parse(&contents, e))?;
+
+        Self::from_saved(saved)
+    }
+
+    fn add_connection(&mut self, innovations: &mut Innovations, rng: &mut impl Rng) {
+        let hidden: Vec<usize> = self.nodes[self.n_outputs..].iter().map(|n| n.id).collect();
+        let num_sources = self.n_inputs + hidden.len();
+        let num_targets = self.nodes.len();
+
+        for _ in 0..ADD_CONNECTION_TRIES {
+            let source_idx = rng.gen_range(0..num_sources);
+            let from = if source_idx < self.n_inputs {
+                source_idx
+            } else {
+                hidden[source_idx - self.n_inputs]
+            };
+            let to = self.nodes[rng.gen_range(0..num_targets)].id;
+
+            let exists = self
+                .connections
+                .iter()
+                .any(|c| c.from == from && c.to == to);
+            if from == to || exists || self.reaches(to, from) {
+                continue;
+            }
+
+            self.connections.push(ConnectionGene {
+                innovation: innovations.connection(from, to),
+                from,
+                to,
+                weight: rng.gen_range(-1.0f32..1.0f32),
+                enabled: true,
+            });
+            self.connections.sort_by_key(|c| c.innovation);
+            return;
+        }
+    }
+
+    fn add_node(&mut self, innovations: &mut Innovations, rng: &mut impl Rng) {
+        let enabled: Vec<usize> = (0..self.connections.len())
+            .filter(|&idx| self.connections[idx].enabled)
+            .collect();
+        if enabled.is_empty() {
+            return;
+        }
+
+        let conn_idx = enabled[rng.gen_range(0..enabled.len())];
+        let (innovation, from, to, weight) = {
+            let conn = &self.connections[conn_idx];
+            (conn.innovation, conn.from, conn.to, conn.weight)
+        };
+        let id = innovations.split(innovation);
+        // Already split once, and the split connection re-enabled since
+        if self.nodes.iter().any(|n| n.id == id) {
+            return;
+        }
+
+        self.connections[conn_idx].enabled = false;
+        self.nodes.push(NodeGene { id, bias: 0.0 });
+        // The new path starts out behaving like the old connection
+        for (from, to, weight) in [(from, id, 1.0), (id, to, weight)] {
+            self.connections.push(ConnectionGene {
+                innovation: innovations.connection(from, to),
+                from,
+                to,
+                weight,
+                enabled: true,
+            });
+        }
+        self.connections.sort_by_key(|c| c.innovation);
+    }
+
+    /// Whether `target` can be reached from `start`, disabled connections included
+    fn reaches(&self, start: usize, target: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![start];
+        while let Some(id) = stack.pop() {
+            if id == target {
+                return true;
+            }
+            if !visited.insert(id) {
+                continue;
+            }
+            stack.extend(
+                self.connections
+                    .iter()
+                    .filter(|c| c.from == id)
+                    .map(|c| c.to),
+            );
+        }
+
+        false
+    }
+
+    fn value_index(&self, id: usize) -> usize {
+        if id < self.n_inputs {
+            return id;
+        }
+
+        self.n_inputs + self.nodes.iter().position(|n| n.id == id).unwrap()
+    }
+
+    /// Recomputes the evaluation order, fails if the connections have a cycle
+    fn rebuild(&mut self) -> Result<(), String> {
+        let node_idx: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, n)| (n.id, idx))
+            .collect();
+
+        self.incoming = vec![Vec::new(); self.nodes.len()];
+        let mut num_pending = vec![0; self.nodes.len()];
+        for (conn_idx, conn) in self.connections.iter().enumerate() {
+            let to = *node_idx
+                .get(&conn.to)
+                .ok_or_else(|| format!("Connection to unknown node {}", conn.to))?;
+            let from = if conn.from < self.n_inputs {
+                conn.from
+            } else {
+                let from = *node_idx
+                    .get(&conn.from)
+                    .ok_or_else(|| format!("Connection from unknown node {}", conn.from))?;
+                num_pending[to] += 1;
+                self.n_inputs + from
+            };
+            if conn.enabled {
+                self.incoming[to].push((from, conn_idx));
+            }
+        }
+
+        // Kahn's algorithm over all connections, disabled ones included
+        self.order.clear();
+        let mut ready: Vec<usize> = (0..self.nodes.len())
+            .filter(|&idx| num_pending[idx] == 0)
+            .collect();
+        while let Some(idx) = ready.pop() {
+            self.order.push(idx);
+            let id = self.nodes[idx].id;
+            for conn in self.connections.iter().filter(|c| c.from == id) {
+                let to = node_idx[&conn.to];
+                num_pending[to] -= 1;
+                if num_pending[to] == 0 {
+                    ready.push(to);
+                }
+            }
+        }
+        if self.order.len() != self.nodes.len() {
+            return Err("Connections have a cycle".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn to_saved(&self) -> SavedNeatNet {
+        SavedNeatNet {
+            version: NEAT_FORMAT_VERSION,
+            n_inputs: self.n_inputs,
+            n_outputs: self.n_outputs,
+            hidden_activation: self.hidden_activation,
+            output_activation: self.output_activation,
+            nodes: self.nodes.clone(),
+            connections: self.connections.clone(),
+        }
+    }
+
+    fn from_saved(saved: SavedNeatNet) -> Result<Self, BrainFileError> {
+        if saved.version < 1 || saved.version > NEAT_FORMAT_VERSION {
+            return Err(BrainFileError::UnsupportedVersion(saved.version));
+        }
+        let malformed = |msg: String| Err(BrainFileError::Malformed(msg));
+        if saved.n_inputs < 1 || saved.n_outputs < 1 {
+            return malformed("Empty layers not allowed".to_string());
+        }
+
+        let mut ids = HashSet::new();
+        for node in saved.nodes.iter() {
+            if node.id < saved.n_inputs || !ids.insert(node.id) {
+                return malformed(format!("Bad or duplicate node id {}", node.id));
+            }
+        }
+        let output_ids = saved.n_inputs..saved.n_inputs + saved.n_outputs;
+        if let Some(id) = output_ids.clone().find(|id| !ids.contains(id)) {
+            return malformed(format!("Output node {} is missing", id));
+        }
+        let mut innovations = HashSet::new();
+        if let Some(conn) = saved
+            .connections
+            .iter()
+            .find(|c| !innovations.insert(c.innovation))
+        {
+            return malformed(format!("Duplicate innovation {}", conn.innovation));
+        }
+
+        // Outputs first, in id order
+        let (mut nodes, hidden): (Vec<NodeGene>, Vec<NodeGene>) = saved
+            .nodes
+            .into_iter()
+            .partition(|n| output_ids.contains(&n.id));
+        nodes.sort_by_key(|n| n.id);
+        nodes.extend(hidden);
+        let mut connections = saved.connections;
+        connections.sort_by_key(|c| c.innovation);
+
+        let mut net = Self {
+            n_inputs: saved.n_inputs,
+            n_outputs: saved.n_outputs,
+            hidden_activation: saved.hidden_activation,
+            output_activation: saved.output_activation,
+            nodes,
+            connections,
+            order: Vec::new(),
+            incoming: Vec::new(),
+        };
+        net.rebuild().map_err(BrainFileError::Malformed)?;
+        Ok(net)
+    }
+}
+
+impl Innovations {
+    /// Knows the connections of the genomes from `NeatNet::new`
+    pub fn new(n_inputs: usize, n_outputs: usize) -> Self {
+        let mut connections = HashMap::new();
+        for from in 0..n_inputs {
+            for to in n_inputs..n_inputs + n_outputs {
+                connections.insert(
+                    (from, to),
+                    initial_innovation(n_inputs, n_outputs, from, to),
+                );
+            }
+        }
+
+        Self {
+            connections,
+            splits: HashMap::new(),
+            next_innovation: (n_inputs * n_outputs) as u64,
+            next_node: n_inputs + n_outputs,
+        }
+    }
+
+    /// Learns the genes of a genome made elsewhere, eg. loaded from a checkpoint
+    pub fn observe(&mut self, net: &NeatNet) {
+        for conn in net.connections.iter() {
+            self.connections
+                .entry((conn.from, conn.to))
+                .or_insert(conn.innovation);
+            self.next_innovation = self.next_innovation.max(conn.innovation + 1);
+        }
+        for node in net.nodes.iter() {
+            self.next_node = self.next_node.max(node.id + 1);
+        }
+
+        // A hidden node is made by splitting a connection into its first
+        // in and out connections, anything else reaching it came later
+        for node in net.nodes[net.n_outputs..].iter() {
+            let first_in = net
+                .connections
+                .iter()
+                .filter(|c| c.to == node.id)
+                .min_by_key(|c| c.innovation);
+            let first_out = net
+                .connections
+                .iter()
+                .filter(|c| c.from == node.id)
+                .min_by_key(|c| c.innovation);
+            let Some((first_in, first_out)) = first_in.zip(first_out) else {
+                continue;
+            };
+            if let Some(&innovation) = self.connections.get(&(first_in.from, first_out.to)) {
+                self.splits.entry(innovation).or_insert(node.id);
+            }
+        }
+    }
+
+    fn connection(&mut self, from: usize, to: usize) -> u64 {
+        let next_innovation = &mut self.next_innovation;
+        *self.connections.entry((from, to)).or_insert_with(|| {
+            *next_innovation += 1;
+            *next_innovation - 1
+        })
+    }
+
+    fn split(&mut self, innovation: u64) -> usize {
+        let next_node = &mut self.next_node;
+        *self.splits.entry(innovation).or_insert_with(|| {
+            *next_node += 1;
+            *next_node - 1
+        })
+    }
+}
+
+impl Default for NeatParams {
+    fn default() -> Self {
+        Self {
+            add_node_rate: 0.03,
+            add_connection_rate: 0.05,
+        }
+    }
+}
+
+impl NeatParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.add_node_rate) {
+            return Err("add_node_rate must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.add_connection_rate) {
+            return Err("add_connection_rate must be between 0 and 1".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl From<NeatNet> for SavedNeatNet {
+    fn from(net: NeatNet) -> Self {
+        net.to_saved()
+    }
+}
+
+impl TryFrom<SavedNeatNet> for NeatNet {
+    type Error = BrainFileError;
+
+    fn try_from(saved: SavedNeatNet) -> Result<Self, Self::Error> {
+        NeatNet::from_saved(saved)
+    }
+}
+
+fn initial_innovation(n_inputs: usize, n_outputs: usize, from: usize, to: usize) -> u64 {
+    (from * n_outputs + to - n_inputs) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn observed_genome_keeps_its_innovations() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut innovations = Innovations::new(3, 2);
+        let mut net = NeatNet::new(
+            3,
+            2,
+            Activation::Tanh,
+            Activation::Sigmoid,
+            &InitParams::default(),
+            &mut rng,
+        );
+        let params = NeatParams {
+            add_node_rate: 1.0,
+            add_connection_rate: 1.0,
+        };
+        for _ in 0..6 {
+            net.mutate_structure(&params, &mut innovations, &mut rng);
+        }
+        assert!(net.nodes.len() > 2 + 3);
+
+        let mut resumed = Innovations::new(3, 2);
+        resumed.observe(&net);
+        assert_eq!(resumed.connections, innovations.connections);
+        assert_eq!(resumed.splits, innovations.splits);
+        assert_eq!(resumed.next_innovation, innovations.next_innovation);
+        assert_eq!(resumed.next_node, innovations.next_node);
+    }
+
+    #[test]
+    fn bad_shapes_are_errors() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let init = InitParams::default();
+        let acts = (Activation::Tanh, Activation::Sigmoid);
+        assert!(matches!(
+            NeatNet::try_new(0, 2, acts.0, acts.1, &init, &mut rng),
+            Err(NetError::EmptyLayer(0))
+        ));
+        assert!(matches!(
+            NeatNet::try_new(3, 0, acts.0, acts.1, &init, &mut rng),
+            Err(NetError::EmptyLayer(1))
+        ));
+
+        let net = NeatNet::try_new(3, 2, acts.0, acts.1, &init, &mut rng).unwrap();
+        assert!(matches!(
+            net.try_predict(&[0.5; 4]),
+            Err(NetError::InputSize {
+                expected: 3,
+                found: 4
+            })
+        ));
+        assert_eq!(net.try_predict(&[0.5; 3]).unwrap(), net.predict(&[0.5; 3]));
+    }
+}
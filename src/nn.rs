@@ -1,115 +1,1357 @@
+use std::{fmt, fs, io, ops::Range, path::Path};
+
 use rand::Rng;
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
 
-const BRAIN_MUTATION_RATE: f32 = 5.0;
-const BRAIN_MUTATION_VARIATION: f32 = 0.5;
+use crate::genome::BrainGraph;
 
-#[derive(Clone)]
+/// Version of the on-disk brain format, bump it whenever `SavedNet` changes.
+/// Version 1 brains predate activations and load as all sigmoid,
+/// versions before 3 are never recurrent
+pub const BRAIN_FORMAT_VERSION: u32 = 3;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "SavedNet", try_from = "SavedNet")]
 pub struct Net {
     n_inputs: usize,
     layers: Vec<Layer>,
 }
 
+/// Weights live in one flat buffer per layer, row major,
+/// each node's bias followed by one weight per input
 #[derive(Clone)]
 struct Layer {
-    nodes: Vec<Vec<f64>>,
+    size: usize,
+    n_inputs: usize,
+    weights: Vec<f32>,
+    /// Elman context weights, per node one weight for each of the layer's
+    /// own outputs on the previous tick. Empty for feedforward layers
+    context: Vec<f32>,
+    activation: Activation,
+}
+
+/// Outputs of every layer on the previous tick, what a recurrent net remembers
+/// between calls to `Net::predict_into`. Feedforward layers keep an empty entry
+pub type Memory = Vec<Vec<f32>>;
+
+/// Values of every layer, inputs first, back to back in one buffer.
+/// Kept between calls to `Net::predict_into` so that inference
+/// doesn't allocate once it has seen the net's shape
+#[derive(Clone, Default)]
+pub struct Activations {
+    values: Vec<f32>,
+    /// Start of each layer in `values`, followed by the end of the last one
+    offsets: Vec<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Activation {
+    #[default]
+    Sigmoid,
+    Tanh,
+    Relu,
+    LeakyRelu,
+    Identity,
+    Softsign,
+}
+
+/// On-disk representation of a `Net`
+#[derive(Serialize, Deserialize)]
+struct SavedNet {
+    version: u32,
+    layer_sizes: Vec<usize>,
+    layers: Vec<SavedLayer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedLayer {
+    #[serde(default)]
+    activation: Activation,
+    biases: Vec<f32>,
+    weights: Vec<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    context: Vec<Vec<f32>>,
+}
+
+/// Noise added to a mutated weight
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MutationNoise {
+    /// Normal with standard deviation `sigma`
+    Gaussian,
+    /// Uniform in `-sigma..sigma`
+    Uniform,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MutationParams {
+    /// Chance each weight is mutated
+    pub rate: f32,
+    pub noise: MutationNoise,
+    pub sigma: f32,
+    /// Chance a mutated weight is redrawn from scratch instead of nudged
+    pub reset_rate: f32,
+    /// Weights are kept within `-clamp..=clamp`
+    pub clamp: Option<f32>,
+}
+
+/// How the weights of a new brain are drawn
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WeightInit {
+    /// Uniform in `-1..1`
+    Uniform,
+    /// Glorot uniform, scaled by the fan in and fan out. Suits sigmoid and tanh
+    Xavier,
+    /// Normal scaled by the fan in. Suits relu
+    He,
+    /// Normal with standard deviation `sigma`
+    SmallNormal { sigma: f32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InitParams {
+    pub weights: WeightInit,
+    /// Biases start at zero instead of being drawn like the weights
+    pub zero_bias: bool,
+    /// New populations are drawn from this seed instead of the simulation
+    /// seed, so that runs with different seeds start from the same brains
+    pub seed: Option<u64>,
+}
+
+/// How two parent genomes are recombined into a child
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrossoverKind {
+    /// Each weight comes from either parent
+    Uniform,
+    /// Each neuron, bias and incoming weights, comes from either parent
+    Neuron,
+    /// Flattened genome is cut once, the tail comes from the other parent
+    SinglePoint,
+}
+
+/// A net built or fed with the wrong shapes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetError {
+    /// Number of layers given, inputs included
+    TooFewLayers(usize),
+    /// Index of the empty layer
+    EmptyLayer(usize),
+    ActivationCount {
+        expected: usize,
+        found: usize,
+    },
+    InputSize {
+        expected: usize,
+        found: usize,
+    },
+    OutputSize {
+        expected: usize,
+        found: usize,
+    },
+    LayerSizes {
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+}
+
+#[derive(Debug)]
+pub enum BrainFileError {
+    Io(io::Error),
+    Truncated,
+    Malformed(String),
+    UnsupportedVersion(u32),
+    LayerSizeMismatch {
+        layer: usize,
+        expected: usize,
+        found: usize,
+    },
+    InvalidNet(NetError),
 }
 
 impl Net {
-    pub fn new(layer_sizes: Vec<usize>) -> Self {
-        if layer_sizes.len() < 2 {
-            panic!("Need at least 2 layers");
-        }
-        for &size in layer_sizes.iter() {
-            if size < 1 {
-                panic!("Empty layers not allowed");
-            }
+    /// `activations` has one entry per layer after the inputs, weights are
+    /// uniform in `-1..1`. Panics on bad shapes, see `try_new`
+    pub fn new(layer_sizes: Vec<usize>, activations: Vec<Activation>, rng: &mut impl Rng) -> Self {
+        Self::with_init(layer_sizes, activations, &InitParams::default(), rng)
+    }
+
+    pub fn try_new(
+        layer_sizes: Vec<usize>,
+        activations: Vec<Activation>,
+        rng: &mut impl Rng,
+    ) -> Result<Self, NetError> {
+        Self::try_with_init(layer_sizes, activations, &InitParams::default(), rng)
+    }
+
+    pub fn with_init(
+        layer_sizes: Vec<usize>,
+        activations: Vec<Activation>,
+        init: &InitParams,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Self::try_with_init(layer_sizes, activations, init, rng).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_with_init(
+        layer_sizes: Vec<usize>,
+        activations: Vec<Activation>,
+        init: &InitParams,
+        rng: &mut impl Rng,
+    ) -> Result<Self, NetError> {
+        validate_layer_sizes(&layer_sizes)?;
+        if activations.len() != layer_sizes.len() - 1 {
+            return Err(NetError::ActivationCount {
+                expected: layer_sizes.len() - 1,
+                found: activations.len(),
+            });
         }
 
         let mut layers = Vec::new();
         let first_layer_size = *layer_sizes.first().unwrap();
         let mut prev_layer_size = first_layer_size;
 
-        for &layer_size in layer_sizes[1..].iter() {
-            layers.push(Layer::new(layer_size, prev_layer_size));
+        for (&layer_size, activation) in layer_sizes[1..].iter().zip(activations) {
+            layers.push(Layer::new(
+                layer_size,
+                prev_layer_size,
+                activation,
+                init,
+                rng,
+            ));
             prev_layer_size = layer_size;
         }
 
-        Self {
+        Ok(Self {
             layers,
             n_inputs: first_layer_size,
+        })
+    }
+
+    /// Same as `with_init` but every hidden layer also feeds its previous outputs back into itself
+    pub fn new_recurrent(
+        layer_sizes: Vec<usize>,
+        activations: Vec<Activation>,
+        init: &InitParams,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut net = Self::with_init(layer_sizes, activations, init, rng);
+        let num_hidden = net.layers.len() - 1;
+        for layer in net.layers[..num_hidden].iter_mut() {
+            let fan_in = layer.n_inputs + layer.size;
+            layer.context = (0..layer.size * layer.size)
+                .map(|_| init.weights.sample(fan_in, layer.size, rng))
+                .collect();
+        }
+
+        net
+    }
+
+    /// Stateless prediction, a recurrent net sees a blank memory.
+    /// Allocates, the simulation uses `predict_into`. Panics on a bad input size
+    pub fn predict(&self, inputs: &[f32]) -> Vec<Vec<f32>> {
+        self.try_predict(inputs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_predict(&self, inputs: &[f32]) -> Result<Vec<Vec<f32>>, NetError> {
+        let mut activations = Activations::default();
+        self.forward(inputs, None, &mut activations)?;
+        Ok(activations.to_columns())
+    }
+
+    /// Prediction into reused buffers, reads and then updates `memory`,
+    /// which should start out as `initial_memory` and be kept between ticks.
+    /// Panics on a bad input size
+    pub fn predict_into(&self, inputs: &[f32], memory: &mut Memory, out: &mut Activations) {
+        self.try_predict_into(inputs, memory, out)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_predict_into(
+        &self,
+        inputs: &[f32],
+        memory: &mut Memory,
+        out: &mut Activations,
+    ) -> Result<(), NetError> {
+        let fits = memory.len() == self.layers.len()
+            && memory
+                .iter()
+                .zip(self.layers.iter())
+                .all(|(m, l)| m.len() == l.memory_size());
+        if !fits {
+            *memory = self.initial_memory();
+        }
+
+        self.forward(inputs, Some(memory), out)
+    }
+
+    /// Runs every row of `inputs` through the net, `outputs` gets one row per input row.
+    /// Stateless like `predict`, `scratch` is reused between rows
+    pub fn predict_batch(
+        &self,
+        inputs: &[f32],
+        outputs: &mut [f32],
+        scratch: &mut Activations,
+    ) -> Result<(), NetError> {
+        let n_outputs = self.num_outputs();
+        let rows = inputs.chunks_exact(self.n_inputs);
+        if !rows.remainder().is_empty() {
+            return Err(NetError::InputSize {
+                expected: (rows.len() + 1) * self.n_inputs,
+                found: inputs.len(),
+            });
+        }
+        if outputs.len() != rows.len() * n_outputs {
+            return Err(NetError::OutputSize {
+                expected: rows.len() * n_outputs,
+                found: outputs.len(),
+            });
+        }
+
+        for (row, out) in rows.zip(outputs.chunks_exact_mut(n_outputs)) {
+            self.forward(row, None, scratch)?;
+            out.copy_from_slice(scratch.output());
         }
+
+        Ok(())
     }
 
-    pub fn predict(&self, inputs: &Vec<f64>) -> Vec<Vec<f64>> {
+    /// Runs each net on its own row of `inputs`, the nets can differ in shape.
+    /// `outputs` gets each net's outputs back to back
+    pub fn predict_many<'a>(
+        nets: impl IntoIterator<Item = &'a Net>,
+        inputs: &[f32],
+        outputs: &mut [f32],
+        scratch: &mut Activations,
+    ) -> Result<(), NetError> {
+        let mut input_start = 0;
+        let mut output_start = 0;
+        for net in nets {
+            let input_end = input_start + net.n_inputs;
+            let output_end = output_start + net.num_outputs();
+            if input_end > inputs.len() {
+                return Err(NetError::InputSize {
+                    expected: input_end,
+                    found: inputs.len(),
+                });
+            }
+            if output_end > outputs.len() {
+                return Err(NetError::OutputSize {
+                    expected: output_end,
+                    found: outputs.len(),
+                });
+            }
+
+            net.forward(&inputs[input_start..input_end], None, scratch)?;
+            outputs[output_start..output_end].copy_from_slice(scratch.output());
+            input_start = input_end;
+            output_start = output_end;
+        }
+
+        Ok(())
+    }
+
+    /// Zeroed memory, what a freshly spawned car starts with
+    pub fn initial_memory(&self) -> Memory {
+        self.layers
+            .iter()
+            .map(|l| vec![0.0; l.memory_size()])
+            .collect()
+    }
+
+    pub fn is_recurrent(&self) -> bool {
+        self.layers.iter().any(|l| l.is_recurrent())
+    }
+
+    pub fn mutate_with(&mut self, params: &MutationParams, rng: &mut impl Rng) {
+        for gene in self.genes_mut() {
+            params.mutate_gene(gene, rng);
+        }
+    }
+
+    pub fn crossover(&self, other: &Net, kind: CrossoverKind, rng: &mut impl Rng) -> Net {
+        match kind {
+            CrossoverKind::Uniform => self.crossover_uniform(other, rng),
+            CrossoverKind::Neuron => self.crossover_neurons(other, rng),
+            CrossoverKind::SinglePoint => self.crossover_single_point(other, rng),
+        }
+    }
+
+    pub fn crossover_uniform(&self, other: &Net, rng: &mut impl Rng) -> Net {
+        self.assert_same_shape(other);
+
+        let mut child = self.clone();
+        for (gene, other_gene) in child.genes_mut().zip(other.genes()) {
+            if rng.gen_bool(0.5) {
+                *gene = *other_gene;
+            }
+        }
+
+        child
+    }
+
+    pub fn crossover_neurons(&self, other: &Net, rng: &mut impl Rng) -> Net {
+        self.assert_same_shape(other);
+
+        let mut child = self.clone();
+        for (layer, other_layer) in child.layers.iter_mut().zip(other.layers.iter()) {
+            for idx in 0..layer.size {
+                if rng.gen_bool(0.5) {
+                    let row = layer.row(idx);
+                    layer.weights[row.clone()].copy_from_slice(&other_layer.weights[row]);
+                    if layer.is_recurrent() {
+                        let row = layer.context_row(idx);
+                        layer.context[row.clone()].copy_from_slice(&other_layer.context[row]);
+                    }
+                }
+            }
+        }
+
+        child
+    }
+
+    pub fn crossover_single_point(&self, other: &Net, rng: &mut impl Rng) -> Net {
+        self.assert_same_shape(other);
+
+        let mut child = self.clone();
+        let cut = rng.gen_range(0..=self.genes().count());
+        for (gene, other_gene) in child.genes_mut().zip(other.genes()).skip(cut) {
+            *gene = *other_gene;
+        }
+
+        child
+    }
+
+    /// Mean absolute difference between the weights of two same shaped nets
+    pub fn distance(&self, other: &Net) -> f32 {
+        self.assert_same_shape(other);
+
+        let (total, count) = self
+            .genes()
+            .zip(other.genes())
+            .fold((0.0, 0), |(total, count), (a, b)| {
+                (total + (a - b).abs(), count + 1)
+            });
+        total / count as f32
+    }
+
+    /// Every node of a layer is connected to every node of the next one
+    pub fn graph(&self, activations: &[Vec<f32>]) -> BrainGraph {
+        let mut edges = Vec::new();
+        for (column, pair) in activations.windows(2).enumerate() {
+            for from in 0..pair[0].len() {
+                for to in 0..pair[1].len() {
+                    edges.push(((column, from), (column + 1, to)));
+                }
+            }
+        }
+
+        BrainGraph {
+            columns: activations.to_vec(),
+            edges,
+        }
+    }
+
+    pub fn layer_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![self.n_inputs];
+        sizes.extend(self.layers.iter().map(|l| l.size));
+        sizes
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.layers.last().unwrap().size
+    }
+
+    pub fn output_activation(&self) -> Activation {
+        self.layers.last().unwrap().activation
+    }
+
+    pub fn check_layer_sizes(&self, expected: &[usize]) -> Result<(), NetError> {
+        let found = self.layer_sizes();
+        if found != expected {
+            return Err(NetError::LayerSizes {
+                expected: expected.to_vec(),
+                found,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BrainFileError> {
+        let contents = ron::ser::to_string_pretty(
+            &self.to_saved(),
+            ron::ser::PrettyConfig::default().depth_limit(3),
+        )
+        .map_err(|e| BrainFileError::Malformed(e.to_string()))?;
+
+        fs::write(path, contents).map_err(BrainFileError::Io)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BrainFileError> {
+        let contents = fs::read_to_string(path).map_err(BrainFileError::Io)?;
+        let saved: SavedNet =
+            ron::from_str(&contents).map_err(|e| BrainFileError::parse(&contents, e))?;
+
+        Self::from_saved(saved)
+    }
+
+    /// All biases and weights, layer by layer and node by node,
+    /// followed by the layer's context weights
+    fn genes(&self) -> impl Iterator<Item = &f32> {
+        self.layers
+            .iter()
+            .flat_map(|l| l.weights.iter().chain(l.context.iter()))
+    }
+
+    fn genes_mut(&mut self) -> impl Iterator<Item = &mut f32> {
+        self.layers
+            .iter_mut()
+            .flat_map(|l| l.weights.iter_mut().chain(l.context.iter_mut()))
+    }
+
+    /// `memory` is `None` for stateless predictions
+    fn forward(
+        &self,
+        inputs: &[f32],
+        mut memory: Option<&mut Memory>,
+        out: &mut Activations,
+    ) -> Result<(), NetError> {
         if inputs.len() != self.n_inputs {
-            panic!("Bad input size");
+            return Err(NetError::InputSize {
+                expected: self.n_inputs,
+                found: inputs.len(),
+            });
         }
 
-        let mut outputs = Vec::new();
-        outputs.push(inputs.clone());
-        for (layer_index, layer) in self.layers.iter().enumerate() {
-            let layer_results = layer.predict(&outputs[layer_index]);
-            outputs.push(layer_results);
+        out.prepare(std::iter::once(self.n_inputs).chain(self.layers.iter().map(|l| l.size)));
+        out.values[..self.n_inputs].copy_from_slice(inputs);
+        for (idx, layer) in self.layers.iter().enumerate() {
+            let (prev, rest) = out.values.split_at_mut(out.offsets[idx + 1]);
+            let layer_inputs = &prev[out.offsets[idx]..];
+            let layer_outputs = &mut rest[..layer.size];
+
+            let layer_memory = memory.as_deref().map(|m| m[idx].as_slice());
+            layer.forward(layer_inputs, layer_memory, layer_outputs);
+            if let Some(memory) = memory.as_deref_mut() {
+                memory[idx].copy_from_slice(&layer_outputs[..layer.memory_size()]);
+            }
         }
 
-        outputs
+        Ok(())
+    }
+
+    fn assert_same_shape(&self, other: &Net) {
+        if self.layer_sizes() != other.layer_sizes() {
+            panic!("Nets have different layer sizes");
+        }
+        let recurrent_layers =
+            |net: &Net| -> Vec<bool> { net.layers.iter().map(|l| l.is_recurrent()).collect() };
+        if recurrent_layers(self) != recurrent_layers(other) {
+            panic!("Nets have different recurrent layers");
+        }
     }
 
-    pub fn mutate(&mut self) {
-        self.layers.iter_mut().for_each(|l| l.mutate());
+    fn to_saved(&self) -> SavedNet {
+        SavedNet {
+            version: BRAIN_FORMAT_VERSION,
+            layer_sizes: self.layer_sizes(),
+            layers: self
+                .layers
+                .iter()
+                .map(|l| SavedLayer {
+                    activation: l.activation,
+                    biases: l
+                        .weights
+                        .chunks_exact(l.n_inputs + 1)
+                        .map(|n| n[0])
+                        .collect(),
+                    weights: l
+                        .weights
+                        .chunks_exact(l.n_inputs + 1)
+                        .map(|n| n[1..].to_vec())
+                        .collect(),
+                    context: l
+                        .context
+                        .chunks_exact(l.size)
+                        .map(<[f32]>::to_vec)
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn from_saved(saved: SavedNet) -> Result<Self, BrainFileError> {
+        if saved.version < 1 || saved.version > BRAIN_FORMAT_VERSION {
+            return Err(BrainFileError::UnsupportedVersion(saved.version));
+        }
+        validate_layer_sizes(&saved.layer_sizes)?;
+        if saved.layers.len() != saved.layer_sizes.len() - 1 {
+            return Err(BrainFileError::Malformed(format!(
+                "Expected {} layers, found {}",
+                saved.layer_sizes.len() - 1,
+                saved.layers.len()
+            )));
+        }
+
+        let mut layers = Vec::new();
+        for (idx, saved_layer) in saved.layers.into_iter().enumerate() {
+            let layer = idx + 1;
+            let prev_layer_size = saved.layer_sizes[idx];
+            let layer_size = saved.layer_sizes[layer];
+
+            for found in [saved_layer.biases.len(), saved_layer.weights.len()] {
+                if found != layer_size {
+                    return Err(BrainFileError::LayerSizeMismatch {
+                        layer,
+                        expected: layer_size,
+                        found,
+                    });
+                }
+            }
+
+            if !saved_layer.context.is_empty() {
+                let context_sizes = std::iter::once(saved_layer.context.len())
+                    .chain(saved_layer.context.iter().map(|c| c.len()));
+                for found in context_sizes {
+                    if found != layer_size {
+                        return Err(BrainFileError::LayerSizeMismatch {
+                            layer,
+                            expected: layer_size,
+                            found,
+                        });
+                    }
+                }
+            }
+
+            let mut weights = Vec::with_capacity(layer_size * (prev_layer_size + 1));
+            for (bias, node_weights) in saved_layer.biases.into_iter().zip(saved_layer.weights) {
+                if node_weights.len() != prev_layer_size {
+                    return Err(BrainFileError::LayerSizeMismatch {
+                        layer,
+                        expected: prev_layer_size,
+                        found: node_weights.len(),
+                    });
+                }
+
+                weights.push(bias);
+                weights.extend(node_weights);
+            }
+            layers.push(Layer {
+                size: layer_size,
+                n_inputs: prev_layer_size,
+                weights,
+                context: saved_layer.context.concat(),
+                activation: saved_layer.activation,
+            });
+        }
+
+        Ok(Self {
+            n_inputs: saved.layer_sizes[0],
+            layers,
+        })
     }
 }
 
-impl Layer {
-    fn new(layer_size: usize, prev_layer_size: usize) -> Self {
-        let mut rng = rand::thread_rng();
-        let mut nodes: Vec<Vec<f64>> = Vec::new();
+impl WeightInit {
+    pub fn sample(&self, fan_in: usize, fan_out: usize, rng: &mut impl Rng) -> f32 {
+        match self {
+            WeightInit::Uniform => rng.gen_range(-1.0f32..1.0f32),
+            WeightInit::Xavier => {
+                let limit = (6.0 / (fan_in + fan_out) as f32).sqrt();
+                rng.gen_range(-limit..limit)
+            }
+            WeightInit::He => rng.sample::<f32, _>(StandardNormal) * (2.0 / fan_in as f32).sqrt(),
+            WeightInit::SmallNormal { sigma } => rng.sample::<f32, _>(StandardNormal) * sigma,
+        }
+    }
+}
 
-        for _ in 0..layer_size {
-            let mut node: Vec<f64> = Vec::new();
-            for _ in 0..prev_layer_size + 1 {
-                let random_weight: f64 = rng.gen_range(-1.0f64..1.0f64);
-                node.push(random_weight);
+impl InitParams {
+    pub fn bias(&self, fan_in: usize, fan_out: usize, rng: &mut impl Rng) -> f32 {
+        if self.zero_bias {
+            0.0
+        } else {
+            self.weights.sample(fan_in, fan_out, rng)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let WeightInit::SmallNormal { sigma } = self.weights {
+            if !(sigma > 0.0 && sigma.is_finite()) {
+                return Err("SmallNormal sigma must be positive".to_string());
             }
-            nodes.push(node);
         }
 
-        Self { nodes }
+        Ok(())
+    }
+}
+
+impl Default for InitParams {
+    fn default() -> Self {
+        Self {
+            weights: WeightInit::Uniform,
+            zero_bias: false,
+            seed: None,
+        }
+    }
+}
+
+impl MutationNoise {
+    fn sample(&self, sigma: f32, rng: &mut impl Rng) -> f32 {
+        match self {
+            MutationNoise::Gaussian => rng.sample::<f32, _>(StandardNormal) * sigma,
+            MutationNoise::Uniform => rng.gen_range(-sigma..sigma),
+        }
+    }
+}
+
+impl MutationParams {
+    /// Mutates a single weight or bias, with probability `rate`
+    pub fn mutate_gene(&self, gene: &mut f32, rng: &mut impl Rng) {
+        if rng.gen::<f32>() >= self.rate {
+            return;
+        }
+
+        if rng.gen::<f32>() < self.reset_rate {
+            *gene = rng.gen_range(-1.0f32..1.0f32);
+        } else {
+            *gene += self.noise.sample(self.sigma, rng);
+        }
+        if let Some(clamp) = self.clamp {
+            *gene = gene.clamp(-clamp, clamp);
+        }
     }
 
-    fn predict(&self, inputs: &Vec<f64>) -> Vec<f64> {
-        let mut layer_results = Vec::new();
-        for node in self.nodes.iter() {
-            layer_results.push(self.sigmoid(self.dot_prod(&node, &inputs)));
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.rate) {
+            return Err("rate must be between 0 and 1".to_string());
+        }
+        if !(self.sigma > 0.0 && self.sigma.is_finite()) {
+            return Err("sigma must be positive".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.reset_rate) {
+            return Err("reset_rate must be between 0 and 1".to_string());
+        }
+        if let Some(clamp) = self.clamp {
+            if clamp.is_nan() || clamp <= 0.0 {
+                return Err("clamp must be positive".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MutationParams {
+    fn default() -> Self {
+        Self {
+            rate: 0.05,
+            noise: MutationNoise::Uniform,
+            sigma: 0.5,
+            reset_rate: 0.0,
+            clamp: None,
+        }
+    }
+}
+
+impl fmt::Display for BrainFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrainFileError::Io(e) => write!(f, "Brain file io error: {}", e),
+            BrainFileError::Truncated => write!(f, "Brain file is truncated"),
+            BrainFileError::Malformed(e) => write!(f, "Malformed brain file: {}", e),
+            BrainFileError::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported brain format version {}, expected at most {}",
+                v, BRAIN_FORMAT_VERSION
+            ),
+            BrainFileError::LayerSizeMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Layer {} size mismatch, expected {} found {}",
+                layer, expected, found
+            ),
+            BrainFileError::InvalidNet(e) => write!(f, "Invalid brain: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BrainFileError {}
+
+impl From<NetError> for BrainFileError {
+    fn from(e: NetError) -> Self {
+        BrainFileError::InvalidNet(e)
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::TooFewLayers(n) => write!(f, "Need at least 2 layers, found {}", n),
+            NetError::EmptyLayer(layer) => write!(f, "Layer {} is empty", layer),
+            NetError::ActivationCount { expected, found } => write!(
+                f,
+                "Need an activation per layer, expected {} found {}",
+                expected, found
+            ),
+            NetError::InputSize { expected, found } => {
+                write!(f, "Bad input size, expected {} found {}", expected, found)
+            }
+            NetError::OutputSize { expected, found } => {
+                write!(f, "Bad output size, expected {} found {}", expected, found)
+            }
+            NetError::LayerSizes { expected, found } => write!(
+                f,
+                "Layer sizes {:?} don't match the expected {:?}",
+                found, expected
+            ),
         }
+    }
+}
+
+impl std::error::Error for NetError {}
 
-        layer_results
+impl From<Net> for SavedNet {
+    fn from(net: Net) -> Self {
+        net.to_saved()
     }
+}
+
+impl TryFrom<SavedNet> for Net {
+    type Error = BrainFileError;
+
+    fn try_from(saved: SavedNet) -> Result<Self, Self::Error> {
+        Net::from_saved(saved)
+    }
+}
 
-    fn mutate(&mut self) {
-        let mut rng = rand::thread_rng();
-        for n in self.nodes.iter_mut() {
-            for val in n.iter_mut() {
-                if rng.gen_range(0.0..1.0) >= BRAIN_MUTATION_RATE {
-                    continue;
+impl Activation {
+    pub fn apply(&self, y: f32) -> f32 {
+        match self {
+            Activation::Sigmoid => 1f32 / (1f32 + (-y).exp()),
+            Activation::Tanh => y.tanh(),
+            Activation::Relu => y.max(0.0),
+            Activation::LeakyRelu => {
+                if y > 0.0 {
+                    y
+                } else {
+                    0.01 * y
                 }
+            }
+            Activation::Identity => y,
+            Activation::Softsign => y / (1.0 + y.abs()),
+        }
+    }
+
+    /// Lowest and highest values `apply` returns, `None` if unbounded
+    pub fn bounds(&self) -> Option<(f32, f32)> {
+        match self {
+            Activation::Sigmoid => Some((0.0, 1.0)),
+            Activation::Tanh | Activation::Softsign => Some((-1.0, 1.0)),
+            Activation::Relu | Activation::LeakyRelu | Activation::Identity => None,
+        }
+    }
+}
 
-                *val += rng.gen_range(-BRAIN_MUTATION_VARIATION..BRAIN_MUTATION_VARIATION) as f64;
+impl Activations {
+    /// Number of layers, inputs included
+    pub fn len(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn layer(&self, idx: usize) -> &[f32] {
+        &self.values[self.offsets[idx]..self.offsets[idx + 1]]
+    }
+
+    pub fn output(&self) -> &[f32] {
+        self.layer(self.len() - 1)
+    }
+
+    /// One `Vec` per layer, for drawing
+    pub fn to_columns(&self) -> Vec<Vec<f32>> {
+        (0..self.len())
+            .map(|idx| self.layer(idx).to_vec())
+            .collect()
+    }
+
+    /// All the values, layer after layer
+    pub(crate) fn values_mut(&mut self) -> &mut [f32] {
+        &mut self.values
+    }
+
+    /// Lays out the buffer for `layer_sizes`, does nothing if it already fits
+    pub(crate) fn prepare(&mut self, layer_sizes: impl Iterator<Item = usize> + Clone) {
+        let fits = self.offsets.len() == layer_sizes.clone().count() + 1
+            && self
+                .offsets
+                .windows(2)
+                .map(|w| w[1] - w[0])
+                .eq(layer_sizes.clone());
+        if fits {
+            return;
+        }
+
+        self.offsets.clear();
+        self.offsets.push(0);
+        let mut end = 0;
+        for size in layer_sizes {
+            end += size;
+            self.offsets.push(end);
+        }
+        self.values.clear();
+        self.values.resize(end, 0.0);
+    }
+}
+
+impl Layer {
+    fn new(
+        layer_size: usize,
+        prev_layer_size: usize,
+        activation: Activation,
+        init: &InitParams,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut weights = Vec::with_capacity(layer_size * (prev_layer_size + 1));
+        for _ in 0..layer_size {
+            weights.push(init.bias(prev_layer_size, layer_size, rng));
+            for _ in 0..prev_layer_size {
+                weights.push(init.weights.sample(prev_layer_size, layer_size, rng));
             }
         }
+
+        Self {
+            size: layer_size,
+            n_inputs: prev_layer_size,
+            weights,
+            context: Vec::new(),
+            activation,
+        }
+    }
+
+    fn is_recurrent(&self) -> bool {
+        !self.context.is_empty()
+    }
+
+    /// Values remembered between ticks, none for feedforward layers
+    fn memory_size(&self) -> usize {
+        if self.is_recurrent() {
+            self.size
+        } else {
+            0
+        }
+    }
+
+    /// Bias and weights of node `idx` in `weights`
+    fn row(&self, idx: usize) -> Range<usize> {
+        let stride = self.n_inputs + 1;
+        idx * stride..(idx + 1) * stride
+    }
+
+    fn context_row(&self, idx: usize) -> Range<usize> {
+        idx * self.size..(idx + 1) * self.size
+    }
+
+    /// `memory` is this layer's output from the previous tick, `None` reads as all zeros
+    fn forward(&self, inputs: &[f32], memory: Option<&[f32]>, outputs: &mut [f32]) {
+        let rows = self.weights.chunks_exact(self.n_inputs + 1);
+        for (idx, (row, output)) in rows.zip(outputs.iter_mut()).enumerate() {
+            let mut total = row[0] + dot_prod(&row[1..], inputs);
+            if let Some(memory) = memory.filter(|_| self.is_recurrent()) {
+                total += dot_prod(&self.context[self.context_row(idx)], memory);
+            }
+            *output = self.activation.apply(total);
+        }
+    }
+}
+
+impl BrainFileError {
+    /// ron reports `Eof` only for files cut between tokens, a file cut
+    /// mid token fails on its last token instead, at the end of the file
+    pub(crate) fn parse(contents: &str, error: ron::error::SpannedError) -> Self {
+        let contents = contents.trim_end();
+        let last_line = contents.lines().count().max(1);
+        let end_col = contents.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        let is_at_end = (error.position.line, error.position.col) >= (last_line, end_col);
+
+        if error.code == ron::Error::Eof || is_at_end {
+            BrainFileError::Truncated
+        } else {
+            BrainFileError::Malformed(error.to_string())
+        }
+    }
+}
+
+/// At least an input and an output layer, none of them empty
+pub(crate) fn validate_layer_sizes(layer_sizes: &[usize]) -> Result<(), NetError> {
+    if layer_sizes.len() < 2 {
+        return Err(NetError::TooFewLayers(layer_sizes.len()));
+    }
+    if let Some(layer) = layer_sizes.iter().position(|&size| size < 1) {
+        return Err(NetError::EmptyLayer(layer));
+    }
+
+    Ok(())
+}
+
+fn dot_prod(weights: &[f32], values: &[f32]) -> f32 {
+    weights.iter().zip(values).map(|(w, v)| w * v).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("steering-{}-{}.ron", std::process::id(), name))
+    }
+
+    fn load_str(name: &str, contents: &str) -> Result<Net, BrainFileError> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let result = Net::load(&path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn test_net() -> Net {
+        let activations = vec![Activation::Tanh, Activation::Sigmoid];
+        Net::new(vec![3, 4, 2], activations, &mut StdRng::seed_from_u64(7))
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let net = test_net();
+        let path = temp_path("round-trip");
+        net.save(&path).unwrap();
+        let loaded = Net::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.layer_sizes(), net.layer_sizes());
+        assert!(loaded.genes().eq(net.genes()));
+        let inputs = [0.1, 0.5, 0.9];
+        assert_eq!(loaded.predict(&inputs), net.predict(&inputs));
     }
 
-    fn dot_prod(&self, node: &Vec<f64>, values: &Vec<f64>) -> f64 {
-        let mut it = node.iter();
-        let mut total = *it.next().unwrap();
-        for (weight, value) in it.zip(values.iter()) {
-            total += weight * value;
+    #[test]
+    fn truncated_file_is_reported() {
+        let contents = ron::to_string(&test_net()).unwrap();
+        let mid_token = contents.find("Sigm").unwrap() + "Sigm".len();
+        for cut in [contents.len() - 1, contents.len() / 2, mid_token] {
+            let result = load_str("truncated", &contents[..cut]);
+            assert!(
+                matches!(result, Err(BrainFileError::Truncated)),
+                "cut at {}",
+                cut
+            );
         }
 
-        total
+        let result = load_str("malformed", &contents.replacen("Sigmoid", "Sigm", 1));
+        assert!(matches!(result, Err(BrainFileError::Malformed(_))));
     }
 
-    fn sigmoid(&self, y: f64) -> f64 {
-        1f64 / (1f64 + (-y).exp())
+    #[test]
+    fn layer_size_mismatch_names_the_layer() {
+        let mut saved = test_net().to_saved();
+        saved.layers[1].weights[0].pop();
+        let contents = ron::to_string(&saved).unwrap();
+        assert!(matches!(
+            load_str("row-mismatch", &contents),
+            Err(BrainFileError::LayerSizeMismatch {
+                layer: 2,
+                expected: 4,
+                found: 3
+            })
+        ));
+
+        let mut saved = test_net().to_saved();
+        saved.layers[0].biases.pop();
+        let contents = ron::to_string(&saved).unwrap();
+        assert!(matches!(
+            load_str("bias-mismatch", &contents),
+            Err(BrainFileError::LayerSizeMismatch {
+                layer: 1,
+                expected: 4,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn version_1_brain_loads_as_sigmoid() {
+        // Written before activations existed, with f64 weights
+        let v1 = "(
+            version: 1,
+            layer_sizes: [2, 2, 1],
+            layers: [
+                (
+                    biases: [0.1234567890123456, -0.5],
+                    weights: [[0.25, -0.7500000000000001], [1.0e-1, 0.3333333333333333]],
+                ),
+                (biases: [0.05], weights: [[-1.25, 0.875]]),
+            ],
+        )";
+        let v3 = v1
+            .replace("version: 1", "version: 3")
+            .replace(
+                "(\n                    biases",
+                "(activation: Sigmoid, biases",
+            )
+            .replace("(biases", "(activation: Sigmoid, biases");
+        assert_eq!(v3.matches("Sigmoid").count(), 2);
+
+        let old = load_str("v1", v1).unwrap();
+        let new = load_str("v1-as-v3", &v3).unwrap();
+        assert!(old
+            .layers
+            .iter()
+            .all(|l| l.activation == Activation::Sigmoid));
+        for inputs in [[0.0, 0.0], [0.3, -0.8], [1.0, 1.0]] {
+            assert_eq!(old.predict(&inputs), new.predict(&inputs));
+        }
+    }
+
+    #[test]
+    fn predict_batch_matches_predict() {
+        let net = test_net();
+        let inputs = [0.1, 0.5, 0.9, -0.3, 0.0, 0.7];
+        let mut outputs = [0.0; 4];
+        let mut scratch = Activations::default();
+        net.predict_batch(&inputs, &mut outputs, &mut scratch)
+            .unwrap();
+
+        for (row, out) in inputs.chunks(3).zip(outputs.chunks(2)) {
+            assert_eq!(net.predict(row).last().unwrap(), out);
+        }
+    }
+
+    #[test]
+    fn predict_batch_checks_sizes() {
+        let net = test_net();
+        let mut scratch = Activations::default();
+        assert!(matches!(
+            net.predict_batch(&[0.0; 5], &mut [0.0; 4], &mut scratch),
+            Err(NetError::InputSize {
+                expected: 6,
+                found: 5
+            })
+        ));
+        assert!(matches!(
+            net.predict_batch(&[0.0; 6], &mut [0.0; 3], &mut scratch),
+            Err(NetError::OutputSize {
+                expected: 4,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn predict_many_matches_predict_per_net() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let init = InitParams::default();
+        let nets = [
+            test_net(),
+            Net::new(vec![2, 5, 3], vec![Activation::Relu; 2], &mut rng),
+            Net::new_recurrent(vec![4, 3, 1], vec![Activation::Tanh; 2], &init, &mut rng),
+        ];
+        let inputs = [0.1, 0.5, 0.9, -0.3, 0.8, 0.2, 0.4, 0.6, -1.0];
+        let mut outputs = [0.0; 6];
+        let mut scratch = Activations::default();
+        Net::predict_many(&nets, &inputs, &mut outputs, &mut scratch).unwrap();
+
+        let mut expected: Vec<f32> = Vec::new();
+        let mut input_start = 0;
+        for net in nets.iter() {
+            let n_inputs = net.layer_sizes()[0];
+            let prediction = net.predict(&inputs[input_start..input_start + n_inputs]);
+            expected.extend(prediction.last().unwrap());
+            input_start += n_inputs;
+        }
+        assert_eq!(outputs.as_slice(), expected.as_slice());
+
+        assert!(matches!(
+            Net::predict_many(&nets, &inputs[..8], &mut outputs, &mut scratch),
+            Err(NetError::InputSize {
+                expected: 9,
+                found: 8
+            })
+        ));
+        assert!(matches!(
+            Net::predict_many(&nets, &inputs, &mut [0.0; 5], &mut scratch),
+            Err(NetError::OutputSize {
+                expected: 6,
+                found: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn recurrent_memory_carries_over_until_reset() {
+        let activations = vec![Activation::Tanh, Activation::Sigmoid];
+        let mut rng = StdRng::seed_from_u64(7);
+        let net = Net::new_recurrent(vec![3, 4, 2], activations, &InitParams::default(), &mut rng);
+        let inputs = [0.1, 0.5, 0.9];
+        let mut memory = net.initial_memory();
+        let mut out = Activations::default();
+
+        net.predict_into(&inputs, &mut memory, &mut out);
+        let first = out.output().to_vec();
+        net.predict_into(&inputs, &mut memory, &mut out);
+        assert_ne!(out.output(), first.as_slice());
+
+        // A newly spawned car starts from a blank memory, same as a stateless prediction
+        let mut memory = net.initial_memory();
+        net.predict_into(&inputs, &mut memory, &mut out);
+        assert_eq!(out.output(), first.as_slice());
+        assert_eq!(net.predict(&inputs).last().unwrap(), &first);
+    }
+
+    #[test]
+    fn crossover_children_take_each_gene_from_a_parent() {
+        let mother = test_net();
+        let activations = vec![Activation::Tanh, Activation::Sigmoid];
+        let father = Net::new(vec![3, 4, 2], activations, &mut StdRng::seed_from_u64(8));
+        let mut rng = StdRng::seed_from_u64(3);
+        // Per gene, whether it came from the mother, the father or neither
+        let origins = |child: &Net| -> Vec<(bool, bool)> {
+            child
+                .genes()
+                .zip(mother.genes().zip(father.genes()))
+                .map(|(gene, (m, f))| (gene == m, gene == f))
+                .collect()
+        };
+
+        for kind in [
+            CrossoverKind::Uniform,
+            CrossoverKind::Neuron,
+            CrossoverKind::SinglePoint,
+        ] {
+            for _ in 0..10 {
+                let child = mother.crossover(&father, kind, &mut rng);
+                let origins = origins(&child);
+                assert!(origins.iter().all(|&(m, f)| m || f), "{:?}", kind);
+
+                match kind {
+                    CrossoverKind::Uniform => {
+                        assert!(origins.iter().any(|&(m, _)| m));
+                        assert!(origins.iter().any(|&(_, f)| f));
+                    }
+                    CrossoverKind::Neuron => {
+                        let layers = child.layers.iter().zip(&mother.layers).zip(&father.layers);
+                        for ((layer, m), f) in layers {
+                            for idx in 0..layer.size {
+                                let row = layer.row(idx);
+                                let genes = &layer.weights[row.clone()];
+                                assert!(
+                                    genes == &m.weights[row.clone()] || genes == &f.weights[row],
+                                    "neuron {} mixes its parents",
+                                    idx
+                                );
+                            }
+                        }
+                    }
+                    CrossoverKind::SinglePoint => {
+                        let cut = origins.iter().position(|&(m, _)| !m);
+                        let cut = cut.unwrap_or(origins.len());
+                        assert!(origins[cut..].iter().all(|&(_, f)| f), "more than one cut");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutation_follows_rate_reset_and_clamp() {
+        let activations = vec![Activation::Sigmoid, Activation::Sigmoid];
+        let mut rng = StdRng::seed_from_u64(11);
+        let net = Net::new(vec![10, 20, 10], activations, &mut rng);
+        let num_genes = net.genes().count();
+        let mutated = |params: MutationParams, rng: &mut StdRng| {
+            let mut child = net.clone();
+            child.mutate_with(&params, rng);
+            child
+        };
+
+        let params = MutationParams {
+            rate: 0.0,
+            ..Default::default()
+        };
+        assert!(mutated(params, &mut rng).genes().eq(net.genes()));
+
+        let params = MutationParams {
+            rate: 0.2,
+            ..Default::default()
+        };
+        let child = mutated(params, &mut rng);
+        let changed = child
+            .genes()
+            .zip(net.genes())
+            .filter(|(a, b)| a != b)
+            .count();
+        let fraction = changed as f32 / num_genes as f32;
+        assert!(
+            (0.15..0.25).contains(&fraction),
+            "{} genes changed",
+            fraction
+        );
+
+        // Noise this large would leave -1..1 unless every gene is redrawn instead
+        let params = MutationParams {
+            rate: 1.0,
+            sigma: 100.0,
+            reset_rate: 1.0,
+            ..Default::default()
+        };
+        let child = mutated(params, &mut rng);
+        assert!(child.genes().all(|g| (-1.0..1.0).contains(g)));
+        assert!(child.genes().zip(net.genes()).all(|(a, b)| a != b));
+
+        let params = MutationParams {
+            rate: 1.0,
+            sigma: 100.0,
+            clamp: Some(0.5),
+            ..Default::default()
+        };
+        let child = mutated(params, &mut rng);
+        assert!(child.genes().all(|g| g.abs() <= 0.5));
+    }
+
+    #[test]
+    fn init_seed_and_zero_bias() {
+        let init = InitParams {
+            weights: WeightInit::Xavier,
+            zero_bias: true,
+            seed: Some(5),
+        };
+        let new_net = |seed: u64| {
+            let activations = vec![Activation::Tanh, Activation::Sigmoid];
+            let mut rng = StdRng::seed_from_u64(seed);
+            Net::new_recurrent(vec![3, 4, 2], activations, &init, &mut rng)
+        };
+        let seed = init.seed.unwrap();
+        assert!(new_net(seed).genes().eq(new_net(seed).genes()));
+        assert!(!new_net(seed).genes().eq(new_net(seed + 1).genes()));
+
+        let net = new_net(seed);
+        for layer in net.layers.iter() {
+            for idx in 0..layer.size {
+                let row = &layer.weights[layer.row(idx)];
+                assert_eq!(row[0], 0.0, "bias of neuron {}", idx);
+                assert!(row[1..].iter().all(|&w| w != 0.0));
+            }
+        }
     }
 }
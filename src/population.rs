@@ -1,10 +1,14 @@
 use bevy::prelude::*;
-use rand::distributions::WeightedIndex;
-use rand::prelude::Distribution;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use crate::car::{Brain, Car, CarBundle, Fitness};
+use crate::car::{Brain, Car, CarBundle, Elite, Fitness};
+use crate::checkpoint::ResumeCheckpoint;
 use crate::enemy::{spawn_bound_trucks, spawn_enemies, BoundControlTruck, Enemy};
-use crate::nn::Net;
+use crate::genome::Genome;
+use crate::neat::Innovations;
+use crate::selection::GenePool;
+use crate::simulation::{SimSet, SimulationTick};
+use crate::species::{SpeciesOffspring, SpeciesTracker};
 use crate::*;
 
 pub struct PopulationPlugin;
@@ -12,30 +16,78 @@ pub struct PopulationPlugin;
 impl Plugin for PopulationPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.insert_resource(MaxDistanceTravelled(0.0))
+            .insert_resource(BrainToDisplay::default())
+            .insert_resource(Settings::default())
+            .insert_resource(SimStats::default())
             .add_startup_system(setup)
-            .add_system(population_stats_system)
-            .add_system(generation_reset_system);
+            .add_system(
+                population_stats_system
+                    .in_set(SimSet::Evaluate)
+                    .in_schedule(SimulationTick),
+            )
+            .add_system(
+                generation_reset_system
+                    .in_set(SimSet::Breed)
+                    .in_schedule(SimulationTick),
+            );
     }
 }
 
-fn setup(mut commands: Commands, mut settings: ResMut<Settings>, asset_server: Res<AssetServer>) {
-    spawn_cars(&mut commands, &asset_server, &mut settings, None);
+fn setup(
+    mut commands: Commands,
+    config: Res<SimConfig>,
+    mut rng: ResMut<SimRng>,
+    mut settings: ResMut<Settings>,
+    mut sim_stats: ResMut<SimStats>,
+    mut resume: ResMut<ResumeCheckpoint>,
+    asset_server: Option<Res<AssetServer>>,
+) {
+    let mut adaptive_mutation = AdaptiveMutation::new(config.evolution.mutation.sigma);
+    let mut species_tracker =
+        SpeciesTracker::new(config.evolution.speciation.compatibility_threshold);
+    let brains = resume.0.take().map(|checkpoint| {
+        sim_stats.generation_count = checkpoint.generation_count;
+        sim_stats.fitness = checkpoint.fitness;
+        adaptive_mutation = checkpoint.adaptive_mutation;
+        species_tracker = checkpoint.species;
+        checkpoint.brains
+    });
+    commands.insert_resource(adaptive_mutation);
+    commands.insert_resource(species_tracker);
+
+    let mut innovations = Innovations::new(config.sensors.num_inputs(), NUM_OUPUT_NODES);
+    for brain in brains.iter().flatten() {
+        if let Genome::Neat(net) = brain {
+            innovations.observe(net);
+        }
+    }
+    commands.insert_resource(innovations);
+
+    spawn_cars(
+        &mut commands,
+        asset_server.as_deref(),
+        &config,
+        &mut rng,
+        &mut settings,
+        brains,
+        0,
+    );
 }
 
 fn population_stats_system(
     mut sim_stats: ResMut<SimStats>,
     mut max_distance_travelled: ResMut<MaxDistanceTravelled>,
     mut brain_on_display: ResMut<BrainToDisplay>,
-    mut query: Query<(&Transform, &Brain, &mut Fitness), With<Car>>,
+    mut query: Query<(Entity, &Transform, &mut Fitness), With<Car>>,
 ) {
     let mut max_fitness = 0.0;
     sim_stats.num_cars_alive = query.iter().len();
 
-    for (transform, brain, mut fitness) in query.iter_mut() {
+    for (entity, transform, mut fitness) in query.iter_mut() {
         fitness.0 = calc_fitness(transform);
         if fitness.0 > max_fitness {
             max_fitness = fitness.0;
-            brain_on_display.0 = brain.nn_outputs.clone();
+            brain_on_display.0 = Some(entity);
             sim_stats.max_current_score = fitness.0;
             max_distance_travelled.0 = transform.translation.y;
         }
@@ -44,9 +96,14 @@ fn population_stats_system(
 
 fn generation_reset_system(
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
+    asset_server: Option<Res<AssetServer>>,
+    config: Res<SimConfig>,
+    mut rng: ResMut<SimRng>,
     mut settings: ResMut<Settings>,
     mut sim_stats: ResMut<SimStats>,
+    mut adaptive_mutation: ResMut<AdaptiveMutation>,
+    mut species_tracker: ResMut<SpeciesTracker>,
+    mut innovations: ResMut<Innovations>,
     cars_query: Query<(Entity, &Brain, &Fitness)>,
     cars_count_query: Query<With<Car>>,
     enemy_query: Query<Entity, With<Enemy>>,
@@ -69,70 +126,162 @@ fn generation_reset_system(
         commands.entity(e).despawn();
     }
 
-    let (max_fitness, gene_pool) = create_gene_pool(fitnesses);
-    let mut rng = rand::thread_rng();
-    let mut new_brains = Vec::new();
+    let gene_pool = GenePool::new(config.evolution.selection, fitnesses.clone());
+    let max_fitness = gene_pool.max_fitness().max(0.0);
+    // Only a new high replaces the best brain, the history is restored when resuming
+    let best_so_far = sim_stats.fitness.iter().copied().fold(f32::MIN, f32::max);
+    if max_fitness > best_so_far {
+        if let Some(&idx) = gene_pool.ranking().first() {
+            sim_stats.best_brain = Some(old_brains[idx].clone());
+        }
+    }
+    adapt_mutation(&mut adaptive_mutation, &config, max_fitness);
+    let mut mutation = config.evolution.mutation.clone();
+    mutation.sigma = adaptive_mutation.sigma;
+
+    // Elites go first, the best brains are kept as is
+    let num_elites = (config.evolution.elite_count as usize).min(old_brains.len());
+    let mut new_brains: Vec<Genome> = gene_pool.ranking()[..num_elites]
+        .iter()
+        .map(|&idx| old_brains[idx].clone())
+        .collect();
+
+    // Without speciation the whole population is a single species
+    let num_offspring = config.num_ai_cars as usize - num_elites;
+    let species = if config.evolution.speciation.enabled {
+        species_tracker.speciate(
+            &old_brains,
+            &fitnesses,
+            num_offspring,
+            &config.evolution.speciation,
+            &mut rng.mutation,
+        )
+    } else {
+        vec![SpeciesOffspring {
+            members: (0..old_brains.len()).collect(),
+            num_offspring,
+        }]
+    };
 
-    for _ in 0..NUM_AI_CARS {
-        let brain_idx = gene_pool.sample(&mut rng);
-        let mut rand_brain = old_brains[brain_idx].clone();
-        rand_brain.mutate();
-        new_brains.push(rand_brain);
+    for species in species {
+        let members = species.members;
+        let species_pool = GenePool::new(
+            config.evolution.selection,
+            members.iter().map(|&idx| fitnesses[idx]).collect(),
+        );
+        for _ in 0..species.num_offspring {
+            let parent = members[species_pool.select(&mut rng.mutation)];
+            let mut rand_brain = if rng.mutation.gen::<f32>() < config.evolution.crossover_rate {
+                // Fitter parent first, NEAT takes the unmatched genes from it
+                let other_parent = members[species_pool.select(&mut rng.mutation)];
+                let (parent, other_parent) = if fitnesses[other_parent] > fitnesses[parent] {
+                    (other_parent, parent)
+                } else {
+                    (parent, other_parent)
+                };
+                old_brains[parent].crossover(
+                    &old_brains[other_parent],
+                    config.evolution.crossover_kind,
+                    &mut rng.mutation,
+                )
+            } else {
+                old_brains[parent].clone()
+            };
+            rand_brain.mutate_with(&mutation, &mut rng.mutation);
+            rand_brain.mutate_structure(
+                &config.evolution.neat,
+                &mut innovations,
+                &mut rng.mutation,
+            );
+            new_brains.push(rand_brain);
+        }
+    }
+
+    // The fresh population of a restart doesn't inherit the old run's progress
+    if settings.restart_sim {
+        *adaptive_mutation = AdaptiveMutation::new(config.evolution.mutation.sigma);
+        *species_tracker = SpeciesTracker::new(config.evolution.speciation.compatibility_threshold);
     }
 
     // update stats
     sim_stats.generation_count += 1;
     sim_stats.fitness.push(max_fitness);
+    info!(
+        "Gen: {}, mutation sigma: {:.3}",
+        sim_stats.generation_count, adaptive_mutation.sigma
+    );
 
     // respawn everything
-    spawn_enemies(&mut commands, &asset_server);
-    spawn_bound_trucks(&mut commands, &asset_server);
+    spawn_enemies(&mut commands, asset_server.as_deref(), &config, &mut rng);
+    spawn_bound_trucks(&mut commands, asset_server.as_deref());
     spawn_cars(
         &mut commands,
-        &asset_server,
+        asset_server.as_deref(),
+        &config,
+        &mut rng,
         &mut settings,
         Some(new_brains),
+        num_elites,
     );
 }
 
+/// Raises sigma while the best fitness is stuck, lowers it back
+/// towards the configured sigma once it improves again
+fn adapt_mutation(adaptive: &mut AdaptiveMutation, config: &SimConfig, max_fitness: f32) {
+    if max_fitness > adaptive.best_fitness {
+        adaptive.best_fitness = max_fitness;
+        adaptive.stagnant_generations = 0;
+    } else {
+        adaptive.stagnant_generations += 1;
+    }
+
+    let settings = &config.evolution.adaptive_mutation;
+    let base_sigma = config.evolution.mutation.sigma;
+    if !settings.enabled {
+        adaptive.sigma = base_sigma;
+    } else if adaptive.stagnant_generations >= settings.stagnation_generations {
+        adaptive.sigma = (adaptive.sigma * settings.factor).min(settings.max_sigma);
+    } else {
+        adaptive.sigma = (adaptive.sigma / settings.factor).max(base_sigma);
+    }
+}
+
 fn spawn_cars(
     commands: &mut Commands,
-    asset_server: &AssetServer,
+    asset_server: Option<&AssetServer>,
+    config: &SimConfig,
+    rng: &mut SimRng,
     settings: &mut Settings,
-    brains: Option<Vec<Net>>,
+    brains: Option<Vec<Genome>>,
+    num_elites: usize,
 ) {
     let brains = brains.unwrap_or(Vec::new());
     let is_new_nn = brains.is_empty() || settings.restart_sim;
     settings.restart_sim = false;
+    let mut init_rng = config.brain.init.seed.map(StdRng::seed_from_u64);
 
-    for i in 0..NUM_AI_CARS {
-        match is_new_nn {
-            true => commands.spawn(CarBundle::new(asset_server)),
-            false => commands.spawn(CarBundle::with_brain(
-                asset_server,
-                &brains.get(i as usize).unwrap(),
-            )),
+    for i in 0..config.num_ai_cars as usize {
+        match brains.get(i) {
+            Some(brain) if !is_new_nn => {
+                let mut car =
+                    commands.spawn(CarBundle::with_brain(asset_server, config, brain, rng));
+                if i < num_elites {
+                    car.insert(Elite);
+                }
+            }
+            _ => match init_rng.as_mut() {
+                Some(init_rng) => {
+                    let brain = Genome::new(config, init_rng);
+                    commands.spawn(CarBundle::with_brain(asset_server, config, &brain, rng));
+                }
+                None => {
+                    commands.spawn(CarBundle::new(asset_server, config, rng));
+                }
+            },
         };
     }
 }
 
-fn create_gene_pool(values: Vec<f32>) -> (f32, WeightedIndex<f32>) {
-    let mut max_fitness = 0.0;
-    let mut weights = Vec::new();
-
-    for v in values.iter() {
-        if *v > max_fitness {
-            max_fitness = *v;
-        }
-        weights.push(*v);
-    }
-
-    (
-        max_fitness,
-        WeightedIndex::new(&weights).expect("Failed to generate gene pool"),
-    )
-}
-
 fn calc_fitness(transform: &Transform) -> f32 {
     let y = transform.translation.y;
     if y <= 600.0 {
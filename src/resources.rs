@@ -1,4 +1,8 @@
 use bevy::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::genome::Genome;
 
 #[derive(Resource, Default)]
 pub struct SimStats {
@@ -6,6 +10,8 @@ pub struct SimStats {
     pub fitness: Vec<f32>,
     pub generation_count: u32,
     pub max_current_score: f32,
+    /// Brain of the fittest car of all the finished generations
+    pub best_brain: Option<Genome>,
 }
 
 #[derive(Resource)]
@@ -17,12 +23,55 @@ pub struct Settings {
     pub is_camera_follow: bool,
 }
 
+/// Car whose brain is drawn by the gui
 #[derive(Resource, Default)]
-pub struct BrainToDisplay(pub Vec<Vec<f64>>);
+pub struct BrainToDisplay(pub Option<Entity>);
 
 #[derive(Resource)]
 pub struct MaxDistanceTravelled(pub f32);
 
+/// Mutation sigma in use, adapted to the fitness progress
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct AdaptiveMutation {
+    pub sigma: f32,
+    pub best_fitness: f32,
+    /// Generations since `best_fitness` last improved
+    pub stagnant_generations: u32,
+}
+
+/// All the simulation randomness, split in independent streams so that
+/// eg. a change in the traffic doesn't shift the mutations
+#[derive(Resource)]
+pub struct SimRng {
+    pub traffic: StdRng,
+    pub mutation: StdRng,
+    pub spawning: StdRng,
+}
+
+impl AdaptiveMutation {
+    /// A new run starts from the configured sigma
+    pub fn new(sigma: f32) -> Self {
+        Self {
+            sigma,
+            best_fitness: 0.0,
+            stagnant_generations: 0,
+        }
+    }
+}
+
+impl SimRng {
+    pub fn from_seed(seed: u64) -> Self {
+        let mut seeder = StdRng::seed_from_u64(seed);
+        let mut stream = || StdRng::from_rng(&mut seeder).expect("Failed to seed rng stream");
+
+        Self {
+            traffic: stream(),
+            mutation: stream(),
+            spawning: stream(),
+        }
+    }
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Self {
@@ -1 +1,76 @@
-struct RoadPlugin;
+use bevy::{math::vec3, prelude::*};
+use bevy_rapier2d::prelude::{Collider, RigidBody};
+
+use crate::*;
+
+pub struct RoadPlugin;
+
+impl Plugin for RoadPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(setup);
+    }
+}
+
+fn setup(mut commands: Commands, asset_server: Option<Res<AssetServer>>) {
+    spawn_roads(&mut commands, asset_server.as_deref());
+}
+
+fn spawn_roads(commands: &mut Commands, asset_server: Option<&AssetServer>) {
+    // Road
+    let rx = WINDOW_WIDTH / 2.0 - 30.0;
+    let mut ry = ROAD_SPRITE_H / 2.0 * SPRITE_SCALE_FACTOR;
+    for _ in 0..NUM_ROAD_TILES {
+        commands.spawn(SpriteBundle {
+            transform: Transform::from_xyz(rx, ry, -10.0)
+                .with_scale(Vec3::splat(SPRITE_SCALE_FACTOR)),
+            texture: load_texture(asset_server, "road.png"),
+            ..default()
+        });
+        ry += ROAD_SPRITE_H * SPRITE_SCALE_FACTOR;
+    }
+    let road_end_y = ry - ROAD_SPRITE_H * SPRITE_SCALE_FACTOR + 800.0;
+
+    // end checker board
+    commands.spawn(SpriteBundle {
+        transform: Transform::from_xyz(rx, road_end_y - 50.0, -5.0)
+            .with_scale(Vec3::splat(SPRITE_SCALE_FACTOR)),
+        texture: load_texture(asset_server, "end-point.png"),
+        ..default()
+    });
+
+    // Road colliders
+    // left
+    let ry = 5.0 * ROAD_SPRITE_H * SPRITE_SCALE_FACTOR;
+    commands.spawn((
+        SpriteBundle {
+            transform: Transform::from_xyz(ROAD_LEFT_X, ry, 0.0).with_scale(vec3(0.5, 0.5, 1.0)),
+            ..default()
+        },
+        RigidBody::Fixed,
+        Collider::cuboid(
+            5.0,
+            ROAD_SPRITE_H * SPRITE_SCALE_FACTOR * NUM_ROAD_TILES as f32 * 5.0,
+        ),
+    ));
+    // right
+    commands.spawn((
+        SpriteBundle {
+            transform: Transform::from_xyz(ROAD_RIGHT_X, ry, 0.0).with_scale(vec3(0.5, 0.5, 1.0)),
+            ..default()
+        },
+        RigidBody::Fixed,
+        Collider::cuboid(
+            5.0,
+            ROAD_SPRITE_H * SPRITE_SCALE_FACTOR * NUM_ROAD_TILES as f32 * 5.0,
+        ),
+    ));
+    // top
+    commands.spawn((
+        SpriteBundle {
+            transform: Transform::from_xyz(600.0, road_end_y, 0.0).with_scale(vec3(0.5, 0.5, 1.0)),
+            ..default()
+        },
+        RigidBody::Fixed,
+        Collider::cuboid(500.0 * SPRITE_SCALE_FACTOR, 10.0),
+    ));
+}
@@ -0,0 +1,231 @@
+use std::cmp::Ordering;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How parents are picked from the previous generation
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SelectionStrategy {
+    /// Chance proportional to fitness
+    Roulette,
+    /// Best of `size` cars picked at random
+    Tournament { size: usize },
+    /// Chance proportional to the rank, the worst car has rank 1
+    LinearRank,
+    /// Uniform among the best `fraction` of the population
+    Truncation { fraction: f32 },
+}
+
+/// Previous generation ranked by fitness, ready to pick parents from
+pub struct GenePool {
+    strategy: SelectionStrategy,
+    fitnesses: Vec<f32>,
+    /// Indices into `fitnesses`, best first
+    ranking: Vec<usize>,
+    roulette_total: f32,
+}
+
+impl SelectionStrategy {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            SelectionStrategy::Tournament { size } if size < 1 => {
+                Err("Tournament size must be at least 1".to_string())
+            }
+            SelectionStrategy::Truncation { fraction } if !(fraction > 0.0 && fraction <= 1.0) => {
+                Err("Truncation fraction must be in (0, 1]".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl GenePool {
+    pub fn new(strategy: SelectionStrategy, fitnesses: Vec<f32>) -> Self {
+        if fitnesses.is_empty() {
+            panic!("Gene pool needs at least one genome");
+        }
+
+        let mut ranking: Vec<usize> = (0..fitnesses.len()).collect();
+        ranking.sort_by(|&a, &b| {
+            fitnesses[b]
+                .partial_cmp(&fitnesses[a])
+                .unwrap_or(Ordering::Equal)
+        });
+        let roulette_total = fitnesses.iter().map(|f| roulette_weight(*f)).sum();
+
+        Self {
+            strategy,
+            fitnesses,
+            ranking,
+            roulette_total,
+        }
+    }
+
+    /// Indices of the genomes, best first
+    pub fn ranking(&self) -> &[usize] {
+        &self.ranking
+    }
+
+    pub fn max_fitness(&self) -> f32 {
+        self.fitnesses[self.ranking[0]]
+    }
+
+    /// Index of a parent
+    pub fn select(&self, rng: &mut impl Rng) -> usize {
+        let n = self.fitnesses.len();
+        // Nothing to tell apart, every genome is as good as any other
+        if self.is_degenerate() {
+            return rng.gen_range(0..n);
+        }
+
+        match self.strategy {
+            SelectionStrategy::Roulette => {
+                let mut target = rng.gen_range(0.0..self.roulette_total);
+                for (idx, fitness) in self.fitnesses.iter().enumerate() {
+                    let weight = roulette_weight(*fitness);
+                    if target < weight {
+                        return idx;
+                    }
+                    target -= weight;
+                }
+                // Float rounding can leave the target just past the end
+                self.ranking[0]
+            }
+            SelectionStrategy::Tournament { size } => (0..size.max(1))
+                .map(|_| rng.gen_range(0..n))
+                .max_by(|&a, &b| {
+                    self.fitnesses[a]
+                        .partial_cmp(&self.fitnesses[b])
+                        .unwrap_or(Ordering::Equal)
+                })
+                .unwrap(),
+            SelectionStrategy::LinearRank => {
+                // Worst gets weight 1, best gets weight n
+                let total = n * (n + 1) / 2;
+                let mut target = rng.gen_range(0..total);
+                for (rank, idx) in self.ranking.iter().enumerate() {
+                    let weight = n - rank;
+                    if target < weight {
+                        return *idx;
+                    }
+                    target -= weight;
+                }
+                unreachable!()
+            }
+            SelectionStrategy::Truncation { fraction } => {
+                let num_parents = ((n as f32 * fraction).ceil() as usize).clamp(1, n);
+                self.ranking[rng.gen_range(0..num_parents)]
+            }
+        }
+    }
+
+    fn is_degenerate(&self) -> bool {
+        let best = self.fitnesses[self.ranking[0]];
+        let worst = self.fitnesses[self.ranking[self.ranking.len() - 1]];
+        if best == worst {
+            return true;
+        }
+
+        match self.strategy {
+            SelectionStrategy::Roulette => {
+                !(self.roulette_total > 0.0 && self.roulette_total.is_finite())
+            }
+            _ => false,
+        }
+    }
+}
+
+fn roulette_weight(fitness: f32) -> f32 {
+    if fitness > 0.0 {
+        fitness
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const STRATEGIES: [SelectionStrategy; 4] = [
+        SelectionStrategy::Roulette,
+        SelectionStrategy::Tournament { size: 3 },
+        SelectionStrategy::LinearRank,
+        SelectionStrategy::Truncation { fraction: 0.3 },
+    ];
+
+    /// Fitness of each index, shuffled so that ranks and indices differ
+    fn fitnesses() -> Vec<f32> {
+        vec![3.0, 9.0, 1.0, 7.0, 5.0, 0.0, 8.0, 2.0, 6.0, 4.0]
+    }
+
+    /// Times each index is picked out of `n` selections
+    fn pick_counts(pool: &GenePool, n: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut counts = vec![0; pool.fitnesses.len()];
+        for _ in 0..n {
+            counts[pool.select(&mut rng)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn ranking_is_best_first() {
+        let pool = GenePool::new(SelectionStrategy::Roulette, fitnesses());
+        assert_eq!(pool.ranking(), &[1, 6, 3, 8, 4, 9, 0, 7, 2, 5]);
+        assert_eq!(pool.max_fitness(), 9.0);
+    }
+
+    #[test]
+    fn truncation_never_picks_below_the_cutoff() {
+        let strategy = SelectionStrategy::Truncation { fraction: 0.3 };
+        let pool = GenePool::new(strategy, fitnesses());
+        let counts = pick_counts(&pool, 1000);
+        for (idx, &count) in counts.iter().enumerate() {
+            if pool.ranking()[..3].contains(&idx) {
+                assert!(count > 0, "parent {} never picked", idx);
+            } else {
+                assert_eq!(count, 0, "parent {} is below the cutoff", idx);
+            }
+        }
+    }
+
+    #[test]
+    fn every_strategy_favours_the_best() {
+        for strategy in STRATEGIES {
+            let pool = GenePool::new(strategy, fitnesses());
+            let counts = pick_counts(&pool, 5000);
+            let (best, worst) = (pool.ranking()[0], pool.ranking()[9]);
+            assert!(counts[best] > counts[worst], "{:?}: {:?}", strategy, counts);
+
+            let mean_pick = counts
+                .iter()
+                .enumerate()
+                .map(|(idx, &count)| pool.fitnesses[idx] * count as f32)
+                .sum::<f32>()
+                / 5000.0;
+            assert!(
+                mean_pick > 4.5,
+                "{:?} picks {} on average",
+                strategy,
+                mean_pick
+            );
+        }
+    }
+
+    #[test]
+    fn equal_fitness_picks_uniformly() {
+        for strategy in STRATEGIES {
+            let pool = GenePool::new(strategy, vec![0.0; 10]);
+            let counts = pick_counts(&pool, 1000);
+            assert!(
+                counts.iter().all(|&c| c > 0),
+                "{:?}: {:?}",
+                strategy,
+                counts
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant};
+
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+use bevy_rapier2d::prelude::*;
+
+use crate::*;
+
+/// Wall time the ticks of a frame may take, a slow or sped up
+/// simulation drops time instead of freezing the window
+const MAX_FRAME_SIM_TIME: Duration = Duration::from_millis(16);
+
+/// Steps the simulation on a fixed timestep, decoupled from rendering
+pub struct SimulationPlugin;
+
+/// One fixed `SIM_TIMESTEP` step of the whole simulation, physics included
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationTick;
+
+/// Stages of a `SimulationTick`, physics runs between `Act` and `Evaluate`
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SimSet {
+    Sense,
+    Act,
+    Evaluate,
+    Breed,
+    Report,
+}
+
+#[derive(Resource)]
+pub struct SimulationClock {
+    pub is_paused: bool,
+    /// Multiple of real time the simulation runs at
+    pub speed: f32,
+    /// Ignore `speed` and run as many ticks as fit in a frame
+    pub is_max_speed: bool,
+    /// Ticks to run while paused
+    pub pending_steps: u32,
+    /// Pause as soon as the next generation starts
+    pub pause_on_next_generation: bool,
+    accumulated_secs: f32,
+}
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        let mut schedule = Schedule::new();
+        schedule
+            .configure_sets(
+                (
+                    PhysicsSet::SyncBackend,
+                    PhysicsSet::SyncBackendFlush,
+                    PhysicsSet::StepSimulation,
+                    PhysicsSet::Writeback,
+                )
+                    .chain(),
+            )
+            .configure_sets(
+                (SimSet::Sense, SimSet::Act)
+                    .chain()
+                    .before(PhysicsSet::SyncBackend),
+            )
+            .configure_sets(
+                (SimSet::Evaluate, SimSet::Breed, SimSet::Report)
+                    .chain()
+                    .after(PhysicsSet::Writeback),
+            )
+            .add_systems(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackend)
+                    .in_base_set(PhysicsSet::SyncBackend),
+            )
+            .add_systems(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackendFlush)
+                    .in_base_set(PhysicsSet::SyncBackendFlush),
+            )
+            .add_systems(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::StepSimulation)
+                    .in_base_set(PhysicsSet::StepSimulation),
+            )
+            .add_systems(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::Writeback)
+                    .in_base_set(PhysicsSet::Writeback),
+            );
+
+        app.add_schedule(SimulationTick, schedule)
+            .insert_resource(SimulationClock::default())
+            .add_startup_system(setup)
+            .add_system(run_simulation_system);
+    }
+}
+
+impl Default for SimulationClock {
+    fn default() -> Self {
+        Self {
+            is_paused: false,
+            speed: 1.0,
+            is_max_speed: false,
+            pending_steps: 0,
+            pause_on_next_generation: false,
+            accumulated_secs: 0.0,
+        }
+    }
+}
+
+impl SimulationClock {
+    fn ticks_for(&mut self, delta_secs: f32) -> u32 {
+        if self.is_paused {
+            self.accumulated_secs = 0.0;
+            return std::mem::take(&mut self.pending_steps);
+        }
+        if self.is_max_speed {
+            return u32::MAX;
+        }
+
+        self.accumulated_secs += delta_secs * self.speed;
+        let ticks = (self.accumulated_secs / SIM_TIMESTEP) as u32;
+        self.accumulated_secs -= ticks as f32 * SIM_TIMESTEP;
+        ticks
+    }
+}
+
+fn setup(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.gravity = Vec2::ZERO;
+    rapier_config.timestep_mode = TimestepMode::Fixed {
+        dt: SIM_TIMESTEP,
+        substeps: 1,
+    };
+}
+
+fn run_simulation_system(world: &mut World) {
+    let delta_secs = world.resource::<Time>().delta_seconds();
+    let ticks = world
+        .resource_mut::<SimulationClock>()
+        .ticks_for(delta_secs);
+
+    let start = Instant::now();
+    for _ in 0..ticks {
+        let generation = world.resource::<SimStats>().generation_count;
+        world.run_schedule(SimulationTick);
+        let is_new_generation = generation != world.resource::<SimStats>().generation_count;
+
+        let mut clock = world.resource_mut::<SimulationClock>();
+        if clock.pause_on_next_generation && is_new_generation {
+            clock.pause_on_next_generation = false;
+            clock.is_paused = true;
+            break;
+        }
+        if start.elapsed() > MAX_FRAME_SIM_TIME {
+            clock.accumulated_secs = 0.0;
+            break;
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::genome::Genome;
+use crate::*;
+
+/// Compatibility threshold is scaled by this to steer towards `target_species`
+const THRESHOLD_ADJUST_FACTOR: f32 = 1.1;
+
+/// Brains grouped by similarity, each group competes mostly within itself
+/// so that a new behavior gets a few generations to improve
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct SpeciesTracker {
+    pub species: Vec<Species>,
+    pub threshold: f32,
+    /// Size of each species per generation, for the gui chart
+    pub history: Vec<Vec<(u32, usize)>>,
+    next_id: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Species {
+    pub id: u32,
+    representative: Genome,
+    best_fitness: f32,
+    /// Generations since `best_fitness` last improved
+    stagnant_generations: u32,
+}
+
+/// Members of a surviving species and the number of children it gets
+pub struct SpeciesOffspring {
+    pub members: Vec<usize>,
+    pub num_offspring: usize,
+}
+
+impl SpeciesTracker {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            species: Vec::new(),
+            threshold,
+            history: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Sorts the brains into species and splits `num_offspring` between them
+    /// by shared fitness, stagnant species get nothing and are dropped
+    pub fn speciate(
+        &mut self,
+        brains: &[Genome],
+        fitnesses: &[f32],
+        num_offspring: usize,
+        config: &SpeciationConfig,
+        rng: &mut impl Rng,
+    ) -> Vec<SpeciesOffspring> {
+        let mut members: Vec<Vec<usize>> = vec![Vec::new(); self.species.len()];
+        for (idx, brain) in brains.iter().enumerate() {
+            let species_idx = self
+                .species
+                .iter()
+                .position(|s| s.representative.distance(brain) < self.threshold);
+            match species_idx {
+                Some(species_idx) => members[species_idx].push(idx),
+                None => {
+                    self.species.push(Species {
+                        id: self.next_id,
+                        representative: brain.clone(),
+                        best_fitness: 0.0,
+                        stagnant_generations: 0,
+                    });
+                    self.next_id += 1;
+                    members.push(vec![idx]);
+                }
+            }
+        }
+
+        // Extinct species
+        let mut species: Vec<(Species, Vec<usize>)> = self
+            .species
+            .drain(..)
+            .zip(members)
+            .filter(|(_, members)| !members.is_empty())
+            .collect();
+        self.history
+            .push(species.iter().map(|(s, m)| (s.id, m.len())).collect());
+
+        let best_idx = (0..fitnesses.len())
+            .max_by(|&a, &b| fitnesses[a].total_cmp(&fitnesses[b]))
+            .unwrap();
+        for (s, members) in species.iter_mut() {
+            let max_fitness = members
+                .iter()
+                .map(|&idx| fitnesses[idx])
+                .fold(0.0, f32::max);
+            if max_fitness > s.best_fitness {
+                s.best_fitness = max_fitness;
+                s.stagnant_generations = 0;
+            } else {
+                s.stagnant_generations += 1;
+            }
+        }
+        // The species holding the best brain always survives
+        species.retain(|(s, members)| {
+            s.stagnant_generations < config.stagnation_generations || members.contains(&best_idx)
+        });
+
+        if config.target_species > 0 {
+            if species.len() > config.target_species as usize {
+                self.threshold *= THRESHOLD_ADJUST_FACTOR;
+            } else if species.len() < config.target_species as usize {
+                self.threshold /= THRESHOLD_ADJUST_FACTOR;
+            }
+        }
+
+        // Fitness sharing, a species earns the average fitness of its members
+        let shares: Vec<f32> = species
+            .iter()
+            .map(|(_, members)| {
+                members
+                    .iter()
+                    .map(|&idx| fitnesses[idx].max(0.0))
+                    .sum::<f32>()
+                    / members.len() as f32
+            })
+            .collect();
+        let allocation = allocate_offspring(&shares, num_offspring);
+
+        let mut offspring = Vec::new();
+        for ((mut s, members), num_offspring) in species.into_iter().zip(allocation) {
+            s.representative = brains[members[rng.gen_range(0..members.len())]].clone();
+            self.species.push(s);
+            offspring.push(SpeciesOffspring {
+                members,
+                num_offspring,
+            });
+        }
+
+        offspring
+    }
+}
+
+/// Splits `total` proportionally to `shares`, leftovers go to the largest remainders
+fn allocate_offspring(shares: &[f32], total: usize) -> Vec<usize> {
+    let sum: f32 = shares.iter().sum();
+    let exact: Vec<f32> = if sum > 0.0 && sum.is_finite() {
+        shares.iter().map(|s| s / sum * total as f32).collect()
+    } else {
+        vec![total as f32 / shares.len() as f32; shares.len()]
+    };
+
+    let mut allocation: Vec<usize> = exact.iter().map(|e| e.floor() as usize).collect();
+    let mut by_remainder: Vec<usize> = (0..exact.len()).collect();
+    by_remainder.sort_by(|&a, &b| exact[b].fract().total_cmp(&exact[a].fract()));
+
+    let leftover = total.saturating_sub(allocation.iter().sum());
+    for &idx in by_remainder.iter().cycle().take(leftover) {
+        allocation[idx] += 1;
+    }
+
+    allocation
+}